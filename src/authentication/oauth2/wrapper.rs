@@ -36,6 +36,13 @@ pub async fn authenticate(datalink: DataLink) -> Res<(TokenSet, DriveData)> {
     Ok((tokenset, drive))
 }
 
+/// Trade the refresh token for a new token set before the access token expires, keeping the new refresh token.
+pub async fn refresh_authentication(datalink: DataLink, tokenset: TokenSet) -> Res<TokenSet> {
+    let tokenset = refresh_tokenset(tokenset.refresh_token).await?;
+    insert_token(datalink, tokenset.refresh_token.clone(), tokenset.absolute_expiration).await?;
+    Ok(tokenset)
+}
+
 pub async fn stateless_authentication() -> Res<(TokenSet, DriveData)> {
     let csrf = generate_csrf();
     let (pkce_verifier, pkce_challenge) = generate_pkce();
@@ -1,57 +1,309 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::spawn;
-use tokio::task::JoinHandle;
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio::io::split;
 use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
 
-use crate::{communication::{NetworkMessage, server::{PORT, IDENTIFIER, Server}}, error::{ChannelError, Res}, util::channel::send};
+use rusqlite_async::database::DataLink;
+
+use crate::database::interface;
+use crate::onedrive::get_album_children::{Album, Photo};
+use crate::{communication::{ConnectionState, NetworkMessage, RequestId, codec::Codec, discovery::{self, DiscoveredDisplay, Group}, handshake, heartbeat::{self, Heartbeat}, outbox::{self, CONTROL_CAPACITY}, requests::PendingRequests, secure::{self, Opener, Sealer}, transfer::{Reassembly, TransferProgress}, transport::Transport, server::Server}, error::{ChannelError, Error, Res}, frontend::application::ApplicationError, util::channel::send};
 use async_channel::Sender;
 use async_channel::Receiver;
-use async_channel::unbounded;
+use async_channel::bounded;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAXIMUM_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How long `Client::request` callers usually wait for the display to answer.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Opens a new transport to the display, for the first connection and for every reconnect.
+pub type Connector = Arc<dyn Fn() -> Pin<Box<dyn Future<Output = Res<Box<dyn Transport>>> + Send>> + Send + Sync>;
+
+/// A transport that has completed both handshakes, with its channel halves and the codec agreed for it.
+type Connection = (Box<dyn Transport>, Sealer, Opener, Codec);
+
+/// The tasks serving one connection. Spawned tasks outlive whatever spawned them, so these are stopped explicitly
+/// when serving the connection is cancelled, as it is when the client is dropped.
+struct ConnectionTasks(Vec<AbortHandle>);
+
+impl Drop for ConnectionTasks {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Client {
     thread: JoinHandle<Res<()>>,
-    sender: Sender<NetworkMessage>
+    router: JoinHandle<Res<()>>,
+    sender: Sender<NetworkMessage>,
+    requests: PendingRequests
 }
 
 impl Client {
 
-    pub async fn spawn() -> Res<(Self, Receiver<NetworkMessage>)> {
-        let (send_to_foreign_sender, send_to_foreign_receiver) = unbounded();
-        let (recv_from_foreign_sender, recv_from_foreign_receiver) = unbounded();
-        let send_to_foreign_sender_clone = send_to_foreign_sender.clone();
-        let target_address = Self::discover().await?;
-        let recv_stream = TcpStream::connect((target_address, PORT)).await?;
+    /// Connect to the display with the given id. A PIN is only needed if this controller has not paired with it before.
+    /// Once connected, the client reconnects by itself whenever the connection drops.
+    pub async fn spawn(database: DataLink, group: Group, display_id: String, pin: Option<String>, heartbeat: Heartbeat) -> Res<(Self, Receiver<NetworkMessage>)> {
+        Self::spawn_over(Self::locator(group, display_id, database.clone()), database, pin, heartbeat).await
+    }
+
+    /// Connect to a display by host name or IP address, for networks where discovery is blocked.
+    /// The group's port is used when the address has none. The address is saved, and reconnecting
+    /// looks for the display by discovery first and at the saved address after that.
+    pub async fn spawn_at(database: DataLink, group: Group, address: String, pin: Option<String>, heartbeat: Heartbeat) -> Res<(Self, Receiver<NetworkMessage>)> {
+        let address = with_port(&address, group.port);
+        let dial_address = address.clone();
+        let dial: Connector = Arc::new(move || Box::pin(Self::dial(dial_address.clone())));
+
+        let (connection, display_id) = Self::connect(&dial, database.clone(), pin).await?;
+        Self::remember(database.clone(), display_id.clone(), None, address).await?;
+
+        Ok(Self::start(connection, Self::locator(group, display_id, database.clone()), database, heartbeat))
+    }
+
+    /// Connect to a display over whatever transport `connector` opens, such as one end of an in-memory pair.
+    pub async fn spawn_over(connector: Connector, database: DataLink, pin: Option<String>, heartbeat: Heartbeat) -> Res<(Self, Receiver<NetworkMessage>)> {
+        let (connection, _) = Self::connect(&connector, database.clone(), pin).await?;
+        Ok(Self::start(connection, connector, database, heartbeat))
+    }
+
+    fn start(connection: Connection, connector: Connector, database: DataLink, heartbeat: Heartbeat) -> (Self, Receiver<NetworkMessage>) {
+        let (send_to_foreign_sender, send_to_foreign_receiver) = bounded(CONTROL_CAPACITY);
+        let (recv_from_foreign_sender, recv_from_foreign_receiver) = bounded(CONTROL_CAPACITY);
+        let (routing_sender, routing_receiver) = bounded(CONTROL_CAPACITY);
+        let requests = PendingRequests::default();
 
-        Ok((
+        (
             Self {
-                thread: spawn(Self::run(recv_stream, recv_from_foreign_sender, send_to_foreign_receiver, send_to_foreign_sender_clone)),
-                sender: send_to_foreign_sender
+                thread: spawn(Self::run(connection, connector, database, routing_sender, send_to_foreign_receiver, heartbeat)),
+                router: spawn(Self::route(routing_receiver, requests.clone(), recv_from_foreign_sender)),
+                sender: send_to_foreign_sender,
+                requests
             },
             recv_from_foreign_receiver
-        ))
+        )
     }
 
-    async fn discover() -> Res<IpAddr> {
-        let addr = udp_discovery::client::discover(IDENTIFIER, PORT).await?;
-        Ok(addr)
+    /// List the displays of a group answering on the LAN.
+    pub async fn discover(group: Group) -> Res<Vec<DiscoveredDisplay>> {
+        discovery::browse(&group).await
     }
 
-    async fn run(recv_stream: TcpStream, output: Sender<NetworkMessage>, input: Receiver<NetworkMessage>, input_sender: Sender<NetworkMessage>) -> Res<()> {
-        let (read_half, write_half) = recv_stream.into_split();
+    /// Opens transports to the display with the given id, wherever it can currently be found.
+    fn locator(group: Group, display_id: String, database: DataLink) -> Connector {
+        Arc::new(move || Box::pin(Self::open(group.clone(), display_id.clone(), database.clone())))
+    }
+
+    /// Find the display, which may have changed address since it was last seen, and connect to it over TCP.
+    /// When discovery does not find it, as on networks that block broadcasts, the address it was last reached on is tried.
+    async fn open(group: Group, display_id: String, database: DataLink) -> Res<Box<dyn Transport>> {
+        let discovered = Self::discover(group)
+            .await
+            .ok()
+            .and_then(|displays| displays.into_iter().find(|display| display.announcement.display_id == display_id));
+
+        match discovered {
+            Some(display) => {
+                let transport = Self::dial(display.address.to_string()).await?;
+                Self::remember(database, display_id, Some(display.announcement.name), display.address.to_string()).await?;
+                Ok(transport)
+            },
+            None => match interface::select_known_display(database, display_id).await? {
+                Some(known) => Self::dial(known.address).await,
+                None => Err(ApplicationError::NoEndpoint.into())
+            }
+        }
+    }
+
+    /// Connect over TCP to a host name or IP address with port.
+    async fn dial(address: String) -> Res<Box<dyn Transport>> {
+        Ok(Box::new(TcpStream::connect(address).await?))
+    }
+
+    /// Save where a display was reached. A display reached by hand keeps the name it was last discovered under, if any.
+    async fn remember(database: DataLink, display_id: String, name: Option<String>, address: String) -> Res<()> {
+        let name = match name {
+            Some(name) => name,
+            None => interface::select_known_display(database.clone(), display_id.clone())
+                .await?
+                .map(|known| known.name)
+                .unwrap_or(address.clone())
+        };
+
+        interface::insert_known_display(database, display_id, name, address).await
+    }
+
+    /// Open a transport and complete both handshakes over it, learning the id of the display at the other end.
+    async fn connect(connector: &Connector, database: DataLink, pin: Option<String>) -> Res<(Connection, String)> {
+        let mut transport = connector().await?;
+        let codec = handshake::perform(&mut transport, None).await?;
+        let (sealer, opener, display_id) = secure::connect(&mut transport, database, pin).await?;
+        Ok(((transport, sealer, opener, codec), display_id))
+    }
+
+    /// Serve connections for the lifetime of the client, reconnecting with exponential backoff.
+    async fn run(connection: Connection, connector: Connector, database: DataLink, output: Sender<NetworkMessage>, input: Receiver<NetworkMessage>, heartbeat: Heartbeat) -> Res<()> {
+        // Requests that describe the state the application is looking at, replayed after reconnecting
+        let state_requests: Arc<Mutex<Vec<NetworkMessage>>> = Arc::new(Mutex::new(Vec::new()));
+        let mut connection = Some(connection);
+
+        loop {
+            if let Some(connection) = connection.take() {
+                send(NetworkMessage::ConnectionState(ConnectionState::Connected), &output).await?;
+
+                let state = Self::serve(connection, output.clone(), input.clone(), state_requests.clone(), heartbeat).await?;
+
+                send(NetworkMessage::ConnectionState(state), &output).await?;
+            }
+
+            let mut backoff = INITIAL_BACKOFF;
+            let mut attempt: u32 = 1;
+
+            while connection.is_none() {
+                send(NetworkMessage::ConnectionState(ConnectionState::Retrying(attempt, backoff.as_secs())), &output).await?;
+                sleep(backoff).await;
+
+                send(NetworkMessage::ConnectionState(ConnectionState::Connecting), &output).await?;
+                match Self::connect(&connector, database.clone(), None).await {
+                    Ok((new_connection, _)) => connection = Some(new_connection),
+                    Err(_) => {
+                        backoff = (backoff * 2).min(MAXIMUM_BACKOFF);
+                        attempt += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Pump messages over a single connection until the display goes away.
+    async fn serve(
+        (transport, sealer, opener, codec): Connection,
+        output: Sender<NetworkMessage>,
+        input: Receiver<NetworkMessage>,
+        state_requests: Arc<Mutex<Vec<NetworkMessage>>>,
+        heartbeat: Heartbeat
+    ) -> Res<ConnectionState> {
+        let replay = state_requests.lock().unwrap().clone();
+        let (read_half, write_half) = split(transport);
+        let (connection_outbox, connection_inbox) = outbox::outbox();
+
+        let mut recv_thread = spawn(Server::recv(read_half, opener, codec, output, Ok, heartbeat, connection_outbox.clone()));
+        let mut send_thread = spawn(Server::send(write_half, sealer, codec, connection_inbox));
+        let ping_thread = spawn(heartbeat::pinger(connection_outbox.clone(), heartbeat));
+        let _tasks = ConnectionTasks(vec![recv_thread.abort_handle(), send_thread.abort_handle(), ping_thread.abort_handle()]);
 
-        let recv_thread = spawn(Server::recv(read_half, output));
-        let send_thread = spawn(Server::send(write_half, input));
+        for message in replay {
+            connection_outbox.push(message).await?;
+        }
 
-        let _ = recv_thread.await;
+        let state = loop {
+            tokio::select! {
+                message = input.recv() => {
+                    let message = message.map_err(ChannelError::from)?;
 
-        // Interupt send thread
-        input_sender.send(NetworkMessage::TerminateThread).await.map_err(ChannelError::from)?;
-        let _ = send_thread.await;
+                    if message.is_state_request() {
+                        let mut state_requests = state_requests.lock().unwrap();
+                        state_requests.retain(|existing| !Self::same_request(existing, &message));
+                        state_requests.push(message.clone());
+                    }
 
+                    connection_outbox.push(message).await?;
+                }
+
+                result = &mut recv_thread => break match result {
+                    Ok(Ok(())) => ConnectionState::Closed,
+                    Ok(Err(Error::ApplicationError(error))) if matches!(error.as_ref(), ApplicationError::PeerTimedOut) => ConnectionState::TimedOut,
+                    _ => ConnectionState::Lost
+                }
+            }
+        };
+
+        ping_thread.abort();
+
+        // Let the send thread flush what is queued, unless the display has stopped reading
+        connection_outbox.close();
+        if timeout(heartbeat.timeout, &mut send_thread).await.is_err() {
+            send_thread.abort();
+        }
+
+        Ok(state)
+    }
+
+    /// Hand replies to the request waiting on them, and pass everything else on to the application.
+    async fn route(input: Receiver<NetworkMessage>, requests: PendingRequests, output: Sender<NetworkMessage>) -> Res<()> {
+        loop {
+            let message = input.recv().await.map_err(ChannelError::from)?;
+
+            if let Some(message) = requests.resolve(message).await {
+                send(message, &output).await?;
+            }
+        }
+    }
+
+    /// Send a request under a fresh id and wait for the reply carrying that id.
+    /// Requests answered by several messages, such as thumbnails, resolve with the first; the rest arrive on the receiver.
+    pub async fn request(self: Arc<Self>, request: impl FnOnce(RequestId) -> NetworkMessage, within: Duration) -> Res<NetworkMessage> {
+        self.requests.request(request, |message| async { Ok(send(message, &self.sender).await?) }, within).await
+    }
+
+    /// Fetch the original of a photo from the display's cache in chunks, reporting progress as they arrive.
+    /// A transfer stopped with `cancel_transfer` ends in an error.
+    pub async fn fetch_photo(self: Arc<Self>, album: Album, photo: Photo, progress: Sender<TransferProgress>) -> Res<Vec<u8>> {
+        let (id, replies) = self.requests.subscribe(
+            |id| NetworkMessage::RequestPhoto(id, album, photo),
+            |message| async { Ok(send(message, &self.sender).await?) }
+        ).await?;
+
+        let mut reassembly = Reassembly::new(id);
+
+        let result = loop {
+            // The timeout applies between chunks, so a large photo may take as long as it needs
+            let reply = match timeout(REQUEST_TIMEOUT, replies.recv()).await {
+                Ok(reply) => reply.map_err(ChannelError::from)?,
+                Err(_) => break Err(ApplicationError::RequestTimedOut(id).into())
+            };
+
+            match reassembly.accept(reply) {
+                Ok(Some(bytes)) => break Ok(bytes),
+                // Nobody watching the progress is not a reason to stop
+                Ok(None) => { let _ = progress.send(reassembly.progress()).await; },
+                Err(e) => break Err(e)
+            }
+        };
+
+        self.requests.forget(id);
+
+        // Stop the display sending the rest of a transfer that failed on this side
+        if result.is_err() {
+            let _ = send(NetworkMessage::CancelTransfer(id), &self.sender).await;
+        }
+
+        result
+    }
+
+    pub async fn cancel_transfer(sender: Sender<NetworkMessage>, id: RequestId) -> Res<()> {
+        send(NetworkMessage::CancelTransfer(id), &sender).await?;
         Ok(())
     }
 
+    /// Whether a newer request supersedes an older one when replaying state.
+    fn same_request(a: &NetworkMessage, b: &NetworkMessage) -> bool {
+        match (a, b) {
+            (NetworkMessage::RequestPhotosInAlbum(_, a), NetworkMessage::RequestPhotosInAlbum(_, b)) => a.id == b.id,
+            (a, b) => std::mem::discriminant(a) == std::mem::discriminant(b)
+        }
+    }
+
     pub fn yield_sender(&self) -> Sender<NetworkMessage> {
         self.sender.clone()
     }
@@ -61,3 +313,52 @@ impl Client {
         Ok(())
     }
 }
+
+/// Add a port to an address entered without one. An IPv6 address with a port is written in brackets, as in [::1]:7878.
+fn with_port(address: &str, port: u16) -> String {
+    let address = address.trim();
+
+    if address.parse::<SocketAddr>().is_ok() {
+        return address.to_string();
+    }
+
+    if let Ok(ip) = address.parse::<IpAddr>() {
+        return SocketAddr::new(ip, port).to_string();
+    }
+
+    match address.rsplit_once(':') {
+        // Only an IPv6 address has several colons outside brackets, such as one with a zone as in fe80::1%eth0
+        Some((host, _)) if host.contains(':') && !address.starts_with('[') => format!("[{address}]:{port}"),
+        Some((_, given)) if given.parse::<u16>().is_ok() => address.to_string(),
+        _ => format!("{address}:{port}")
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // Stop reconnecting once the application lets go of the client
+        self.thread.abort();
+        self.router.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_without_a_port_get_the_default() {
+        assert_eq!(with_port("192.168.1.20", 7878), "192.168.1.20:7878");
+        assert_eq!(with_port("  display.local ", 7878), "display.local:7878");
+        assert_eq!(with_port("::1", 7878), "[::1]:7878");
+        assert_eq!(with_port("[::1]", 7878), "[::1]:7878");
+        assert_eq!(with_port("fe80::1%eth0", 7878), "[fe80::1%eth0]:7878");
+    }
+
+    #[test]
+    fn addresses_with_a_port_are_kept() {
+        assert_eq!(with_port("192.168.1.20:9000", 7878), "192.168.1.20:9000");
+        assert_eq!(with_port("display.local:9000", 7878), "display.local:9000");
+        assert_eq!(with_port("[::1]:9000", 7878), "[::1]:9000");
+    }
+}
@@ -0,0 +1,379 @@
+use std::io::{Read, Write};
+
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::communication::NetworkMessage;
+use crate::communication::handshake::Hello;
+use crate::error::Res;
+use crate::frontend::application::ApplicationError;
+
+/// Payloads smaller than this are sent as they are, compressing them gains too little to be worth it.
+const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Largest frame accepted when none is configured, well above a photo chunk or a long album listing.
+pub const DEFAULT_MAX_FRAME: usize = 16 * 1024 * 1024;
+
+const DEFLATE_CAPABILITY: &str = "deflate";
+const PREFER_PREFIX: &str = "prefer-codec:";
+
+// Leading byte of every payload, saying whether the rest is compressed
+const PLAIN: u8 = 0;
+const DEFLATED: u8 = 1;
+
+/// How a NetworkMessage is written into a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    // Compact, but only readable by a peer built from the same NetworkMessage layout
+    Rkyv,
+    // Serde's JSON representation, for inspecting traffic and for clients not written in Rust
+    Json
+}
+
+impl Encoding {
+    /// In order of preference when neither peer asks for one.
+    pub const ALL: [Encoding; 2] = [Encoding::Rkyv, Encoding::Json];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Rkyv => "rkyv",
+            Encoding::Json => "json"
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Encoding> {
+        Encoding::ALL.into_iter().find(|encoding| encoding.name() == name)
+    }
+
+    fn capability(&self) -> String {
+        format!("codec:{}", self.name())
+    }
+}
+
+/// The encoding and compression agreed with the peer of a connection, and the largest frame taken from it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Codec {
+    pub encoding: Encoding,
+    pub compression: bool,
+    // Applies to the frame as read and to its payload once inflated
+    pub max_frame: usize
+}
+
+impl Default for Codec {
+    fn default() -> Codec {
+        Codec { encoding: Encoding::Rkyv, compression: false, max_frame: DEFAULT_MAX_FRAME }
+    }
+}
+
+/// Capabilities announcing the codecs this build speaks, and the encoding it would like to use if any.
+pub fn capabilities(preferred: Option<Encoding>) -> Vec<String> {
+    Encoding::ALL
+        .iter()
+        .map(Encoding::capability)
+        .chain(std::iter::once(String::from(DEFLATE_CAPABILITY)))
+        .chain(preferred.map(|encoding| format!("{PREFER_PREFIX}{}", encoding.name())))
+        .collect()
+}
+
+/// Pick the codec for a connection from both hellos. Both peers reach the same answer without another round trip:
+/// an encoding either peer prefers wins if both speak it, JSON first should they disagree, otherwise the first shared one in `Encoding::ALL`.
+pub fn negotiate(local: &Hello, remote: &Hello) -> Res<Codec> {
+    let shared: Vec<Encoding> = Encoding::ALL
+        .into_iter()
+        .filter(|encoding| local.supports(&encoding.capability()) && remote.supports(&encoding.capability()))
+        .collect();
+
+    let mut preferred: Vec<Encoding> = local.capabilities
+        .iter()
+        .chain(&remote.capabilities)
+        .filter_map(|capability| capability.strip_prefix(PREFER_PREFIX).and_then(Encoding::parse))
+        .filter(|encoding| shared.contains(encoding))
+        .collect();
+    preferred.sort_by_key(|encoding| *encoding != Encoding::Json);
+
+    let encoding = preferred
+        .first()
+        .or(shared.first())
+        .copied()
+        .ok_or(ApplicationError::NoCommonCodec)?;
+
+    Ok(Codec {
+        encoding,
+        compression: local.supports(DEFLATE_CAPABILITY) && remote.supports(DEFLATE_CAPABILITY),
+        max_frame: DEFAULT_MAX_FRAME
+    })
+}
+
+impl Codec {
+    /// flag (u8, 1 when deflated) | message in the agreed encoding
+    pub fn encode(&self, message: &NetworkMessage) -> Res<Vec<u8>> {
+        let encoded = match self.encoding {
+            Encoding::Rkyv => message.to_bytes()?,
+            Encoding::Json => serde_json::to_vec(message)?
+        };
+
+        if self.compression && message.is_compressible() && encoded.len() >= COMPRESSION_THRESHOLD {
+            let mut encoder = DeflateEncoder::new(vec![DEFLATED], Compression::default());
+            encoder.write_all(&encoded)?;
+            return Ok(encoder.finish()?);
+        }
+
+        let mut payload = Vec::with_capacity(encoded.len() + 1);
+        payload.push(PLAIN);
+        payload.extend_from_slice(&encoded);
+        Ok(payload)
+    }
+
+    /// Anything that does not decode into a message, however it fails, is a `MalformedFrame`.
+    pub fn decode(&self, payload: &[u8]) -> Res<NetworkMessage> {
+        let (flag, body) = payload.split_first().ok_or(ApplicationError::MalformedFrame)?;
+
+        let inflated;
+        let encoded = match *flag {
+            PLAIN => body,
+            DEFLATED if self.compression => {
+                // Stop inflating one byte past the limit, so a small frame cannot expand without bound
+                let mut buffer = Vec::new();
+                DeflateDecoder::new(body)
+                    .take(self.max_frame as u64 + 1)
+                    .read_to_end(&mut buffer)
+                    .map_err(|_| ApplicationError::MalformedFrame)?;
+                if buffer.len() > self.max_frame {
+                    return Err(ApplicationError::FrameTooLarge(buffer.len()).into());
+                }
+                inflated = buffer;
+                &inflated
+            },
+            _ => return Err(ApplicationError::MalformedFrame.into())
+        };
+
+        let message = match self.encoding {
+            Encoding::Rkyv => NetworkMessage::from_bytes(encoded).ok(),
+            Encoding::Json => serde_json::from_slice(encoded).ok()
+        };
+
+        Ok(message.ok_or(ApplicationError::MalformedFrame)?)
+    }
+
+    /// length (u32 BE) | CRC-32 of the sealed bytes (u32 BE) | sealed bytes
+    pub async fn write_frame(&self, stream: &mut (impl AsyncWrite + Unpin), sealed: &[u8]) -> Res<()> {
+        let mut header = Vec::with_capacity(8);
+        header.extend_from_slice(&(sealed.len() as u32).to_be_bytes());
+        header.extend_from_slice(&crc32fast::hash(sealed).to_be_bytes());
+
+        stream.write_all(&header).await?;
+        stream.write_all(sealed).await?;
+        Ok(())
+    }
+
+    /// Read one frame, refusing it before allocating anything if it is larger than `max_frame`,
+    /// and refusing it if it does not match its checksum.
+    pub async fn read_frame(&self, stream: &mut (impl AsyncRead + Unpin)) -> Res<Vec<u8>> {
+        let length = stream.read_u32().await? as usize;
+        let checksum = stream.read_u32().await?;
+
+        if length > self.max_frame {
+            return Err(ApplicationError::FrameTooLarge(length).into());
+        }
+
+        let mut sealed = vec![0u8; length];
+        stream.read_exact(&mut sealed).await?;
+
+        if crc32fast::hash(&sealed) != checksum {
+            return Err(ApplicationError::FrameCorrupted.into());
+        }
+
+        Ok(sealed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use tokio::io::AsyncWriteExt;
+
+    use crate::communication::roles::{Controller, Role};
+    use crate::communication::transport;
+    use crate::communication::{DisplayEvent, DisplayState, NetworkMessage, PlaybackState, SyncState};
+    use crate::error::Error;
+    use crate::frontend::application::ApplicationError;
+    use crate::onedrive::get_album_children::{Album, LocationData, Photo, SyncReport};
+
+    use super::{Codec, Encoding};
+
+    const ROUNDS: usize = 256;
+
+    /// A generator seeded from REFLECTION_TEST_SEED, or at random. The seed is printed, which the test harness shows
+    /// only for a failing test, so a failure can be reproduced.
+    fn seeded() -> StdRng {
+        let seed = std::env::var("REFLECTION_TEST_SEED")
+            .ok()
+            .and_then(|seed| seed.parse().ok())
+            .unwrap_or_else(rand::random);
+
+        println!("REFLECTION_TEST_SEED={seed}");
+        StdRng::seed_from_u64(seed)
+    }
+
+    fn codecs() -> Vec<Codec> {
+        Encoding::ALL
+            .into_iter()
+            .flat_map(|encoding| [false, true].map(|compression| Codec { encoding, compression, ..Codec::default() }))
+            .collect()
+    }
+
+    fn string(rng: &mut StdRng) -> String {
+        let length = rng.random_range(0..48);
+        (0..length).map(|_| rng.random_range(' '..='~')).collect()
+    }
+
+    fn bytes(rng: &mut StdRng) -> Vec<u8> {
+        let length = rng.random_range(0..4096);
+        (0..length).map(|_| rng.random()).collect()
+    }
+
+    fn album(rng: &mut StdRng) -> Album {
+        Album { id: rng.random_range(0..1 << 20), onedrive_id: string(rng), name: string(rng), share_link: string(rng) }
+    }
+
+    fn photo(rng: &mut StdRng) -> Photo {
+        Photo {
+            id: rng.random_range(0..1 << 20),
+            onedrive_id: string(rng),
+            name: string(rng),
+            creation_date: rng.random_bool(0.5).then(|| rng.random()),
+            width: rng.random_range(0..8192),
+            height: rng.random_range(0..8192),
+            filesize: rng.random_range(0..1 << 30),
+            location: rng.random_bool(0.5).then(|| LocationData {
+                altitude: rng.random_bool(0.5).then(|| rng.random_range(-500.0..9000.0)),
+                latitude: rng.random_range(-90.0..=90.0),
+                longitude: rng.random_range(-180.0..=180.0)
+            })
+        }
+    }
+
+    fn role(rng: &mut StdRng) -> Role {
+        [Role::Owner, Role::Viewer, Role::Guest][rng.random_range(0..3)]
+    }
+
+    fn controller(rng: &mut StdRng) -> Controller {
+        Controller {
+            controller_id: string(rng),
+            role: role(rng),
+            paired_at: rng.random(),
+            expires_at: rng.random_bool(0.5).then(|| rng.random()),
+            connected: rng.random()
+        }
+    }
+
+    fn message(rng: &mut StdRng) -> NetworkMessage {
+        match rng.random_range(0..17) {
+            0 => NetworkMessage::Sharelink(rng.random(), string(rng)),
+            1 => NetworkMessage::PlayAlbum(album(rng)),
+            2 => NetworkMessage::SetPlayback([PlaybackState::Stopped, PlaybackState::Playing, PlaybackState::Paused][rng.random_range(0..3)]),
+            3 => NetworkMessage::RequestThumbnail(rng.random(), album(rng), photo(rng)),
+            4 => NetworkMessage::SetRole(rng.random(), string(rng), role(rng)),
+            5 => NetworkMessage::ReturnAllAlbums(rng.random(), (0..rng.random_range(0..64)).map(|_| album(rng)).collect()),
+            6 => NetworkMessage::ReturnPhotosInAlbum(rng.random(), album(rng), (0..rng.random_range(0..64)).map(|_| photo(rng)).collect()),
+            7 => NetworkMessage::Thumbnail(rng.random(), photo(rng), bytes(rng)),
+            8 => NetworkMessage::ReturnActiveAlbum(rng.random_bool(0.5).then(|| rng.random()), rng.random_bool(0.5).then(|| album(rng))),
+            9 => NetworkMessage::ReturnState(rng.random(), DisplayState {
+                album: rng.random_bool(0.5).then(|| album(rng)),
+                photo: rng.random_bool(0.5).then(|| photo(rng)),
+                playback: PlaybackState::Playing,
+                sync: SyncState::Failed(string(rng)),
+                authenticated: rng.random()
+            }),
+            10 => NetworkMessage::Event(DisplayEvent::SyncChanged(SyncState::Finished(SyncReport {
+                photos: rng.random(),
+                added: rng.random(),
+                updated: rng.random(),
+                removed: rng.random(),
+                deleted: rng.random()
+            }))),
+            11 => NetworkMessage::PhotoChunk(rng.random(), rng.random(), bytes(rng)),
+            12 => NetworkMessage::RequestFailed(rng.random(), string(rng)),
+            13 => NetworkMessage::ReturnControllers(rng.random(), (0..rng.random_range(0..64)).map(|_| controller(rng)).collect()),
+            14 => NetworkMessage::PermissionDenied(role(rng)),
+            15 => NetworkMessage::ThumbnailsEnd(rng.random()),
+            _ => NetworkMessage::Ping(rng.random())
+        }
+    }
+
+    fn is_frame_error(error: &Error) -> bool {
+        matches!(error, Error::ApplicationError(error) if matches!(error.as_ref(), ApplicationError::MalformedFrame | ApplicationError::FrameTooLarge(_) | ApplicationError::FrameCorrupted))
+    }
+
+    #[test]
+    fn messages_survive_every_codec() {
+        let mut rng = seeded();
+        for _ in 0..ROUNDS {
+            let message = message(&mut rng);
+            for codec in codecs() {
+                let decoded = codec.decode(&codec.encode(&message).unwrap()).unwrap();
+                assert_eq!(format!("{decoded:?}"), format!("{message:?}"), "{codec:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn damaged_payloads_are_refused_without_panicking() {
+        let mut rng = seeded();
+        for _ in 0..ROUNDS {
+            let message = message(&mut rng);
+            for codec in codecs() {
+                let mut payload = codec.encode(&message).unwrap();
+                payload.truncate(rng.random_range(0..=payload.len()));
+                for _ in 0..rng.random_range(0..8) {
+                    let index = rng.random_range(0..payload.len().max(1));
+                    if let Some(byte) = payload.get_mut(index) {
+                        *byte = rng.random();
+                    }
+                }
+
+                // Damage can leave a different but valid message, only errors are checked
+                if let Err(error) = codec.decode(&payload) {
+                    assert!(is_frame_error(&error), "{error:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn inflating_past_the_limit_is_refused() {
+        let codec = Codec { compression: true, max_frame: 4096, ..Codec::default() };
+        let message = NetworkMessage::ReturnAllAlbums(0, (0..256).map(|_| Album {
+            id: 0,
+            onedrive_id: String::new(),
+            name: "a".repeat(64),
+            share_link: String::new()
+        }).collect());
+
+        let payload = Codec { max_frame: usize::MAX, ..codec }.encode(&message).unwrap();
+        assert!(payload.len() < codec.max_frame);
+        assert!(matches!(codec.decode(&payload), Err(Error::ApplicationError(error)) if matches!(error.as_ref(), ApplicationError::FrameTooLarge(_))));
+    }
+
+    #[tokio::test]
+    async fn frames_are_bounded_and_checked() {
+        let codec = Codec { max_frame: 1024, ..Codec::default() };
+        let (mut writer, mut reader) = transport::memory();
+
+        codec.write_frame(&mut writer, &[7; 1024]).await.unwrap();
+        assert_eq!(codec.read_frame(&mut reader).await.unwrap(), vec![7; 1024]);
+
+        codec.write_frame(&mut writer, &[7; 1025]).await.unwrap();
+        assert!(matches!(codec.read_frame(&mut reader).await, Err(Error::ApplicationError(error)) if matches!(error.as_ref(), ApplicationError::FrameTooLarge(1025))));
+
+        let (mut writer, mut reader) = transport::memory();
+        let mut frame = Vec::new();
+        codec.write_frame(&mut frame, b"sealed").await.unwrap();
+        *frame.last_mut().unwrap() ^= 1;
+        writer.write_all(&frame).await.unwrap();
+        assert!(matches!(codec.read_frame(&mut reader).await, Err(Error::ApplicationError(error)) if matches!(error.as_ref(), ApplicationError::FrameCorrupted)));
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::time::Duration;
+
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use rand::{Rng, rng};
+use rusqlite_async::database::DataLink;
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::time::{Instant, sleep, timeout_at};
+
+use crate::communication::handshake::PROTOCOL_VERSION;
+use crate::database::interface::{insert_setting, select_setting};
+use crate::error::Res;
+
+pub const DEFAULT_IDENTIFIER: &str = "reflection";
+pub const DEFAULT_PORT: u16 = 7878;
+
+/// IPv6 has no broadcast, probes go to every node on the link instead.
+const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+
+const DISPLAY_ID_SETTING: &str = "display_id";
+const DISPLAY_NAME_SETTING: &str = "display_name";
+const DEFAULT_DISPLAY_NAME: &str = "Reflection";
+
+/// How long a controller listens for answers to a discovery probe.
+pub const BROWSE_DURATION: Duration = Duration::from_secs(2);
+
+/// The DNS-SD service type displays advertise, so that standard tools such as `avahi-browse` or `dns-sd` find them too.
+/// Every group shares it and is told apart by the `group` TXT record.
+pub const SERVICE_TYPE: &str = "_reflection._tcp.local.";
+
+/// How often the advertised TXT records are compared against the display's current status.
+const ADVERTISE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long the beacon waits after a socket error before listening again.
+const BEACON_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Displays and controllers that can see each other. A display serves and answers probes on `port`,
+/// and only answers probes carrying its `identifier`, so separate fleets can share a network.
+#[derive(Clone, Debug)]
+pub struct Group {
+    pub identifier: String,
+    pub port: u16
+}
+
+impl Default for Group {
+    fn default() -> Group {
+        Group {
+            identifier: String::from(DEFAULT_IDENTIFIER),
+            port: DEFAULT_PORT
+        }
+    }
+}
+
+/// What a display is doing right now, shown next to its name when choosing a display.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct DisplayStatus {
+    pub authenticated: bool,
+    pub active_album: Option<String>,
+    pub controllers: usize
+}
+
+/// The reply a display sends to a discovery probe.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Announcement {
+    pub display_id: String,
+    pub name: String,
+    pub port: u16,
+    pub status: DisplayStatus
+}
+
+/// A display that answered a probe, with the address its service is reached on.
+#[derive(Serialize, Clone, Debug)]
+pub struct DiscoveredDisplay {
+    pub address: SocketAddr,
+    pub announcement: Announcement
+}
+
+/// A display this controller has reached before, remembered so it can be found again when discovery is blocked.
+#[derive(Serialize, Clone, Debug)]
+pub struct KnownDisplay {
+    pub display_id: String,
+    pub name: String,
+    // Host name or IP address with port, as entered by hand or as last discovered
+    pub address: String,
+    pub last_seen: usize
+}
+
+/// Load the stable identifier of this display. An explicitly configured id replaces the stored one,
+/// otherwise one is generated on first launch. Controllers key their pairings on this id.
+pub async fn display_id(database: DataLink, configured: Option<String>) -> Res<String> {
+    match (configured, select_setting(database.clone(), DISPLAY_ID_SETTING).await?) {
+        (Some(display_id), _) => {
+            insert_setting(database, DISPLAY_ID_SETTING, display_id.clone()).await?;
+            Ok(display_id)
+        }
+        (None, Some(display_id)) => Ok(display_id),
+        (None, None) => {
+            let display_id = BASE64_URL_SAFE_NO_PAD.encode(rng().random::<[u8; 16]>());
+            insert_setting(database, DISPLAY_ID_SETTING, display_id.clone()).await?;
+            Ok(display_id)
+        }
+    }
+}
+
+/// Load the friendly name of this display, saving a newly configured one.
+pub async fn display_name(database: DataLink, configured: Option<String>) -> Res<String> {
+    match (configured, select_setting(database.clone(), DISPLAY_NAME_SETTING).await?) {
+        (Some(name), _) => {
+            insert_setting(database, DISPLAY_NAME_SETTING, name.clone()).await?;
+            Ok(name)
+        }
+        (None, Some(name)) => Ok(name),
+        (None, None) => Ok(String::from(DEFAULT_DISPLAY_NAME))
+    }
+}
+
+/// Answer every probe carrying the group's identifier with the current announcement.
+/// Probes arrive by broadcast or multicast, so the beacon listens on every interface of the family of `bind`.
+/// On a dual-stack host an IPv6 beacon hears IPv4 probes as well.
+pub async fn beacon(group: Group, bind: IpAddr, announce: impl Fn() -> Announcement) -> Res<()> {
+    let unspecified = match bind {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(unspecified, group.port)).await?;
+    let mut buf = vec![0u8; 512];
+
+    // Errors such as an interface going down or an unreachable prober only affect one probe, so the beacon keeps answering
+    loop {
+        let (bytes_received, addr) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(error) => {
+                eprintln!("Beacon failed to receive a probe: {error:?}");
+                sleep(BEACON_RETRY_DELAY).await;
+                continue;
+            }
+        };
+
+        if &buf[..bytes_received] == group.identifier.as_bytes() {
+            let reply = serde_json::to_vec(&announce())?;
+            if let Err(error) = socket.send_to(&reply, addr).await {
+                eprintln!("Beacon failed to answer {addr}: {error:?}");
+                sleep(BEACON_RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Advertise the display as a DNS-SD service, with its name, protocol version and status in TXT records.
+/// The records are registered again whenever the status changes. The advertisement is withdrawn when the future is dropped.
+pub async fn advertise(group: Group, bind: IpAddr, announce: impl Fn() -> Announcement) -> Res<()> {
+    let mut advertiser = Advertiser { daemon: ServiceDaemon::new()?, fullname: None };
+    let mut advertised = None;
+
+    loop {
+        let announcement = announce();
+        let properties = txt_records(&group, &announcement);
+
+        if advertised.as_ref() != Some(&properties) {
+            let host = format!("{}.local.", announcement.display_id);
+            let service = match bind.is_unspecified() {
+                true => ServiceInfo::new(SERVICE_TYPE, &announcement.display_id, &host, (), announcement.port, properties.as_slice())?.enable_addr_auto(),
+                false => ServiceInfo::new(SERVICE_TYPE, &announcement.display_id, &host, bind, announcement.port, properties.as_slice())?
+            };
+
+            advertiser.fullname = Some(service.get_fullname().to_string());
+            advertiser.daemon.register(service)?;
+            advertised = Some(properties);
+        }
+
+        sleep(ADVERTISE_INTERVAL).await;
+    }
+}
+
+/// Sends goodbyes for the advertised service and stops the mDNS daemon.
+struct Advertiser {
+    daemon: ServiceDaemon,
+    fullname: Option<String>
+}
+
+impl Drop for Advertiser {
+    fn drop(&mut self) {
+        if let Some(fullname) = &self.fullname {
+            let _ = self.daemon.unregister(fullname);
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+fn txt_records(group: &Group, announcement: &Announcement) -> Vec<(String, String)> {
+    let mut records = vec![
+        (String::from("id"), announcement.display_id.clone()),
+        (String::from("name"), announcement.name.clone()),
+        (String::from("version"), PROTOCOL_VERSION.to_string()),
+        (String::from("group"), group.identifier.clone()),
+        (String::from("authenticated"), announcement.status.authenticated.to_string()),
+        (String::from("controllers"), announcement.status.controllers.to_string())
+    ];
+
+    if let Some(album) = &announcement.status.active_album {
+        records.push((String::from("album"), album.clone()));
+    }
+
+    records
+}
+
+/// Probe for the displays of a group over IPv4, IPv6 and DNS-SD, collecting every display that answers within the browse duration.
+/// A network without IPv6 or multicast simply finds nothing over it, and only fails to browse when the IPv4 broadcast fails as well.
+pub async fn browse(group: &Group) -> Res<Vec<DiscoveredDisplay>> {
+    let deadline = Instant::now() + BROWSE_DURATION;
+
+    let (ipv4, ipv6, dns_sd) = tokio::join!(
+        probe(group, SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), group.port), deadline),
+        probe(group, SocketAddr::new(IpAddr::V6(ALL_NODES), group.port), deadline),
+        resolve(group, deadline)
+    );
+
+    let ipv4 = match (ipv4, dns_sd.as_ref().is_ok_and(|displays| !displays.is_empty())) {
+        (Ok(displays), _) => displays,
+        (Err(_), true) => Vec::new(),
+        (Err(error), false) => return Err(error)
+    };
+
+    // A display found several ways is listed once, preferring the address it answered a probe from, then IPv4
+    let mut displays: HashMap<String, DiscoveredDisplay> = HashMap::new();
+    for display in dns_sd.unwrap_or_default().into_iter().chain(ipv6.unwrap_or_default()).chain(ipv4) {
+        displays.insert(display.announcement.display_id.clone(), display);
+    }
+
+    let mut displays: Vec<DiscoveredDisplay> = displays.into_values().collect();
+    displays.sort_by(|a, b| a.announcement.name.cmp(&b.announcement.name));
+    Ok(displays)
+}
+
+/// Send a single probe to `target` and collect the answers until the deadline.
+async fn probe(group: &Group, target: SocketAddr, deadline: Instant) -> Res<Vec<DiscoveredDisplay>> {
+    let local = match target {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.set_broadcast(true)?;
+    socket.send_to(group.identifier.as_bytes(), target).await?;
+
+    let mut displays = Vec::new();
+    let mut buf = vec![0u8; 2048];
+
+    while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (bytes_received, addr) = received?;
+
+        // Anything that is not an announcement is some other service sharing the port
+        if let Ok(announcement) = serde_json::from_slice::<Announcement>(&buf[..bytes_received]) {
+            displays.push(DiscoveredDisplay { address: service_address(addr, announcement.port), announcement });
+        }
+    }
+
+    Ok(displays)
+}
+
+/// Browse for displays of the group advertised over DNS-SD until the deadline.
+async fn resolve(group: &Group, deadline: Instant) -> Res<Vec<DiscoveredDisplay>> {
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+    let mut displays = Vec::new();
+
+    while let Ok(event) = timeout_at(deadline, events.recv_async()).await {
+        match event {
+            Ok(ServiceEvent::ServiceResolved(service)) => displays.extend(resolved_display(group, &service)),
+            Ok(_) => {},
+            Err(_) => break
+        }
+    }
+
+    let _ = daemon.shutdown();
+    Ok(displays)
+}
+
+/// Read a display back from its DNS-SD records, skipping other groups and services without a usable address.
+fn resolved_display(group: &Group, service: &ServiceInfo) -> Option<DiscoveredDisplay> {
+    if service.get_property_val_str("group")? != group.identifier {
+        return None;
+    }
+
+    // Advertised IPv6 addresses carry no interface, so a link-local one may not be reachable
+    let ip = service.get_addresses().iter().min_by_key(|ip| ip.is_ipv6())?;
+    let announcement = Announcement {
+        display_id: service.get_property_val_str("id")?.to_string(),
+        name: service.get_property_val_str("name")?.to_string(),
+        port: service.get_port(),
+        status: DisplayStatus {
+            authenticated: service.get_property_val_str("authenticated") == Some("true"),
+            active_album: service.get_property_val_str("album").map(String::from),
+            controllers: service.get_property_val_str("controllers").and_then(|controllers| controllers.parse().ok()).unwrap_or_default()
+        }
+    };
+
+    Some(DiscoveredDisplay { address: SocketAddr::new(*ip, announcement.port), announcement })
+}
+
+/// The address a display's service is reached on, keeping the interface of a link-local IPv6 answer.
+fn service_address(answered_from: SocketAddr, port: u16) -> SocketAddr {
+    match answered_from {
+        SocketAddr::V4(addr) => SocketAddr::new(IpAddr::V4(*addr.ip()), port),
+        SocketAddr::V6(addr) => match addr.ip().to_ipv4_mapped() {
+            Some(ipv4) => SocketAddr::new(IpAddr::V4(ipv4), port),
+            None => SocketAddr::V6(SocketAddrV6::new(*addr.ip(), port, 0, addr.scope_id()))
+        }
+    }
+}
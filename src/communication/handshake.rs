@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+
+use crate::communication::codec::{self, Codec, Encoding};
+use crate::communication::transport::Transport;
+use crate::error::Res;
+use crate::frontend::application::ApplicationError;
+
+/// Leading bytes of every hello, used to reject anything that is not a reflection peer.
+pub const MAGIC: [u8; 4] = *b"RFLX";
+
+/// Bumped whenever the layout of NetworkMessage or its framing changes. rkyv layouts are not self describing, so peers must match exactly.
+pub const PROTOCOL_VERSION: u16 = 17;
+
+/// Optional features supported by this build, advertised to the peer.
+pub const CAPABILITIES: &[&str] = &["albums", "thumbnails", "tokenset"];
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The first message on every connection. Encoded by hand rather than with rkyv so that it stays readable across versions.
+#[derive(Clone, Debug)]
+pub struct Hello {
+    pub version: u16,
+    pub capabilities: Vec<String>
+}
+
+impl Hello {
+    /// Our hello, asking for `preferred` as the wire encoding when given.
+    pub fn local(preferred: Option<Encoding>) -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: CAPABILITIES
+                .iter()
+                .map(|capability| capability.to_string())
+                .chain(codec::capabilities(preferred))
+                .collect()
+        }
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|own| own == capability)
+    }
+
+    /// MAGIC | version (u16 BE) | capability count (u8) | per capability: length (u8) + utf8 bytes
+    /// Fails rather than truncating if there are more than 255 capabilities or one is longer than 255 bytes.
+    fn to_bytes(&self) -> Res<Vec<u8>> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&self.version.to_be_bytes());
+        bytes.push(short_length(self.capabilities.len())?);
+
+        for capability in &self.capabilities {
+            bytes.push(short_length(capability.len())?);
+            bytes.extend_from_slice(capability.as_bytes());
+        }
+
+        Ok(bytes)
+    }
+
+    async fn read(stream: &mut impl Transport) -> Res<Hello> {
+        let mut magic = [0u8; 4];
+        stream.read_exact(&mut magic).await?;
+        if magic != MAGIC { return Err(ApplicationError::NotReflectionPeer.into()); }
+
+        let mut version = [0u8; 2];
+        stream.read_exact(&mut version).await?;
+        let version = u16::from_be_bytes(version);
+
+        let count = stream.read_u8().await?;
+        let mut capabilities = Vec::with_capacity(count as usize);
+
+        for _ in 0..count {
+            let length = stream.read_u8().await?;
+            let mut capability = vec![0u8; length as usize];
+            stream.read_exact(&mut capability).await?;
+            capabilities.push(String::from_utf8_lossy(&capability).to_string());
+        }
+
+        Ok(Hello { version, capabilities })
+    }
+}
+
+fn short_length(length: usize) -> Res<u8> {
+    u8::try_from(length).map_err(|_| ApplicationError::FieldTooLong(length).into())
+}
+
+/// Send our hello and wait for the peer's, agreeing on the codec for the rest of the connection.
+/// Fails if the peer is not a reflection instance or speaks a different protocol version.
+pub async fn perform(stream: &mut impl Transport, preferred: Option<Encoding>) -> Res<Codec> {
+    let local = Hello::local(preferred);
+    let exchange = async {
+        stream.write_all(&local.to_bytes()?).await?;
+        Hello::read(stream).await
+    };
+
+    let remote = timeout(HANDSHAKE_TIMEOUT, exchange).await.map_err(|_| ApplicationError::HandshakeTimeout)??;
+
+    if remote.version != PROTOCOL_VERSION {
+        return Err(ApplicationError::IncompatibleProtocol(PROTOCOL_VERSION, remote.version).into());
+    }
+
+    codec::negotiate(&local, &remote)
+}
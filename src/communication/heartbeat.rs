@@ -0,0 +1,33 @@
+use std::time::Duration;
+
+use tokio::time::sleep;
+
+use crate::communication::NetworkMessage;
+use crate::communication::outbox::Outbox;
+
+/// How often each side pings the other, and how long a silent peer is tolerated before the connection is torn down.
+#[derive(Clone, Copy, Debug)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration
+}
+
+impl Default for Heartbeat {
+    fn default() -> Heartbeat {
+        Heartbeat {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(15)
+        }
+    }
+}
+
+/// Queue a ping on the connection every interval until the connection's send queue closes.
+pub async fn pinger(sender: Outbox<NetworkMessage>, heartbeat: Heartbeat) {
+    let mut sequence: u64 = 0;
+
+    loop {
+        sleep(heartbeat.interval).await;
+        if sender.push(NetworkMessage::Ping(sequence)).await.is_err() { break; }
+        sequence += 1;
+    }
+}
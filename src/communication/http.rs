@@ -0,0 +1,224 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use async_channel::{Receiver, Sender};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use rand::{Rng, rng};
+use ring::hmac::{self, HMAC_SHA256};
+use rusqlite_async::database::DataLink;
+use serde::Serialize;
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio::spawn;
+
+use crate::communication::client::REQUEST_TIMEOUT;
+use crate::communication::discovery::Announcement;
+use crate::communication::requests::PendingRequests;
+use crate::communication::server::PeerId;
+use crate::communication::{NetworkMessage, RequestId};
+use crate::database::interface::{insert_setting, select_setting};
+use crate::error::{Error, Res};
+use crate::frontend::application::ApplicationError;
+use crate::onedrive::get_album_children::{Album, Photo};
+use crate::util::channel::send;
+
+/// Peer id under which API requests reach the application. Never handed to a controller.
+pub const API_PEER: PeerId = PeerId::MAX;
+
+const API_TOKEN_SETTING: &str = "api_token";
+
+/// Shared by every HTTP connection.
+#[derive(Clone)]
+struct Api {
+    // The token is only ever compared as a MAC under a key of its own, so the comparison takes constant time
+    token: (hmac::Key, hmac::Tag),
+    output: Sender<(PeerId, NetworkMessage)>,
+    requests: PendingRequests,
+    announce: Arc<dyn Fn() -> Announcement + Send + Sync>
+}
+
+/// Load the bearer token HTTP clients must present, generating one on first use.
+pub async fn api_token(database: DataLink) -> Res<String> {
+    match select_setting(database.clone(), API_TOKEN_SETTING).await? {
+        Some(token) => Ok(token),
+        None => {
+            let token = BASE64_URL_SAFE_NO_PAD.encode(rng().random::<[u8; 24]>());
+            insert_setting(database, API_TOKEN_SETTING, token.clone()).await?;
+            Ok(token)
+        }
+    }
+}
+
+/// Serve the JSON API. Requests are passed to the application as if they came from the peer `API_PEER`,
+/// and the application's replies to that peer arrive on `replies`. Fails if the address cannot be bound.
+pub async fn serve(
+    address: SocketAddr,
+    token: String,
+    output: Sender<(PeerId, NetworkMessage)>,
+    replies: Receiver<NetworkMessage>,
+    announce: impl Fn() -> Announcement + Send + Sync + 'static
+) -> Res<()> {
+    let listener = TcpListener::bind(address).await?;
+    let key = hmac::Key::new(HMAC_SHA256, &rng().random::<[u8; 32]>());
+    let token = (key.clone(), hmac::sign(&key, token.as_bytes()));
+    let api = Api { token, output, requests: PendingRequests::default(), announce: Arc::new(announce) };
+
+    let requests = api.requests.clone();
+    let dispatcher = spawn(async move {
+        // Broadcasts and late replies have nobody to go to
+        while let Ok(reply) = replies.recv().await {
+            requests.resolve(reply).await;
+        }
+    });
+
+    while let Ok((tcp_stream, _)) = listener.accept().await {
+        let api = api.clone();
+
+        spawn(http1::Builder::new()
+            .timer(TokioTimer::new())
+            .serve_connection(
+                TokioIo::new(tcp_stream),
+                service_fn(move |request| {
+                    let api = api.clone();
+                    async move { Ok::<_, Infallible>(api.handle(request).await) }
+                })
+            ));
+    }
+
+    dispatcher.abort();
+    Ok(())
+}
+
+impl Api {
+
+    async fn handle(&self, request: Request<Incoming>) -> Response<Full<Bytes>> {
+        if !self.authorised(&request) {
+            return respond(StatusCode::UNAUTHORIZED, "application/json", json!({ "error": "missing or invalid bearer token" }).to_string());
+        }
+
+        let segments: Vec<&str> = request.uri().path().trim_matches('/').split('/').collect();
+
+        let result = match (request.method(), segments.as_slice()) {
+            (&Method::GET, ["status"]) => Ok(json_response(&(self.announce)())),
+            (&Method::GET, ["albums"]) => self.albums().await.map(|albums| json_response(&albums)),
+            (&Method::GET, ["albums", album_id, "photos"]) => self.photos(album_id).await.map(|(_, photos)| json_response(&photos)),
+            (&Method::POST, ["albums", album_id, "play"]) => self.play(album_id).await.map(|album| json_response(&album)),
+            (&Method::GET, ["albums", album_id, "photos", photo_id, "thumbnail"]) => self.thumbnail(album_id, photo_id).await
+                .map(|(photo, bytes)| respond(StatusCode::OK, content_type(&photo.name), bytes)),
+            _ => Ok(respond(StatusCode::NOT_FOUND, "application/json", json!({ "error": "no such endpoint" }).to_string()))
+        };
+
+        result.unwrap_or_else(error_response)
+    }
+
+    fn authorised(&self, request: &Request<Incoming>) -> bool {
+        request.headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| hmac::verify(&self.token.0, token.as_bytes(), self.token.1.as_ref()).is_ok())
+    }
+
+    async fn request(&self, request: impl FnOnce(RequestId) -> NetworkMessage) -> Res<NetworkMessage> {
+        self.requests.request(request, |message| async { Ok(send((API_PEER, message), &self.output).await?) }, REQUEST_TIMEOUT).await
+    }
+
+    async fn albums(&self) -> Res<Vec<Album>> {
+        match self.request(NetworkMessage::RequestAllAlbums).await? {
+            NetworkMessage::ReturnAllAlbums(_, albums) => Ok(albums),
+            _ => Err(ApplicationError::UnexpectedReply.into())
+        }
+    }
+
+    async fn album(&self, album_id: &str) -> Res<Album> {
+        self.albums()
+            .await?
+            .into_iter()
+            .find(|album| album.id.to_string() == album_id || album.onedrive_id == album_id)
+            .ok_or(ApplicationError::NoSuchAlbum.into())
+    }
+
+    async fn photos(&self, album_id: &str) -> Res<(Album, Vec<Photo>)> {
+        let album = self.album(album_id).await?;
+
+        match self.request(|id| NetworkMessage::RequestPhotosInAlbum(id, album)).await? {
+            NetworkMessage::ReturnPhotosInAlbum(_, album, photos) => Ok((album, photos)),
+            _ => Err(ApplicationError::UnexpectedReply.into())
+        }
+    }
+
+    async fn play(&self, album_id: &str) -> Res<Option<Album>> {
+        let album = self.album(album_id).await?;
+        send((API_PEER, NetworkMessage::PlayAlbum(album)), &self.output).await?;
+
+        // Handled in order by the application, so the answer reflects the album just played
+        match self.request(NetworkMessage::RequestActiveAlbum).await? {
+            NetworkMessage::ReturnActiveAlbum(_, album) => Ok(album),
+            _ => Err(ApplicationError::UnexpectedReply.into())
+        }
+    }
+
+    async fn thumbnail(&self, album_id: &str, photo_id: &str) -> Res<(Photo, Vec<u8>)> {
+        let (album, photos) = self.photos(album_id).await?;
+        let photo = photos
+            .into_iter()
+            .find(|photo| photo.id.to_string() == photo_id || photo.onedrive_id == photo_id)
+            .ok_or(ApplicationError::NoSuchPhoto)?;
+
+        match self.request(|id| NetworkMessage::RequestThumbnail(id, album, photo)).await? {
+            NetworkMessage::Thumbnail(_, photo, bytes) => Ok((photo, bytes)),
+            _ => Err(ApplicationError::UnexpectedReply.into())
+        }
+    }
+}
+
+fn respond(status: StatusCode, content_type: &str, body: impl Into<Bytes>) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(body.into()));
+    *response.status_mut() = status;
+
+    if let Ok(value) = content_type.parse() {
+        response.headers_mut().insert(CONTENT_TYPE, value);
+    }
+
+    response
+}
+
+fn json_response(body: &impl Serialize) -> Response<Full<Bytes>> {
+    match serde_json::to_string(body) {
+        Ok(body) => respond(StatusCode::OK, "application/json", body),
+        Err(e) => error_response(e.into())
+    }
+}
+
+fn error_response(error: Error) -> Response<Full<Bytes>> {
+    let status = match &error {
+        Error::ApplicationError(application_error) => match application_error.as_ref() {
+            ApplicationError::NoSuchAlbum | ApplicationError::NoSuchPhoto => StatusCode::NOT_FOUND,
+            ApplicationError::RequestTimedOut(_) => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR
+        },
+        _ => StatusCode::INTERNAL_SERVER_ERROR
+    };
+
+    respond(status, "application/json", json!({ "error": format!("{error:?}") }).to_string())
+}
+
+/// Thumbnails are stored in the format of the original photo.
+fn content_type(name: &str) -> &'static str {
+    match name.rsplit('.').next().map(str::to_lowercase).as_deref() {
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("bmp") => "image/bmp",
+        _ => "application/octet-stream"
+    }
+}
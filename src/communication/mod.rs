@@ -1,43 +1,241 @@
-use rkyv::{Archive, Deserialize, Serialize};
+use rkyv::{Archive, Deserialize, Serialize, util::AlignedVec};
 
-use crate::{authentication::oauth2::api::TokenSet, error::Res, onedrive::get_album_children::{Album, Photo}};
+use crate::{authentication::oauth2::api::TokenSet, communication::roles::{Controller, Role}, error::Res, onedrive::get_album_children::{Album, Photo, SyncReport}};
 
 pub mod server;
 pub mod client;
+pub mod codec;
+pub mod discovery;
+pub mod handshake;
+pub mod heartbeat;
+pub mod http;
+pub mod outbox;
+pub mod requests;
+pub mod roles;
+pub mod secure;
+pub mod spake2;
+pub mod transfer;
+pub mod transport;
 
-#[derive(Serialize, Deserialize, Archive, Clone, Debug)]
+/// Chosen by the controller for each request and echoed by every reply to it.
+pub type RequestId = u64;
+
+#[derive(Serialize, Deserialize, Archive, serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub enum NetworkMessage {
 
     // Client to server
     TokenSet(TokenSet),
-    Sharelink(String),
+    // Add the album behind a share link, answered once it has been synced
+    Sharelink(RequestId, String),
     PlayAlbum(Album),
+    SetPlayback(PlaybackState),
 
-    RequestAllAlbums,
-    RequestPhotosInAlbum(Album),
-    RequestThumbnails,
-    RequestActiveAlbum,
+    RequestAllAlbums(RequestId),
+    RequestPhotosInAlbum(RequestId, Album),
+    RequestThumbnails(RequestId),
+    RequestThumbnail(RequestId, Album, Photo),
+    // Stream the original of a photo from the display's cache, and stop streaming it
+    RequestPhoto(RequestId, Album, Photo),
+    CancelTransfer(RequestId),
+    RequestActiveAlbum(RequestId),
+    RequestState(RequestId),
+    // Managing the controllers paired with the display, owners only. A guest PIN lasts the given number of minutes, at most a day
+    RequestControllers(RequestId),
+    SetRole(RequestId, String, Role),
+    RequestGuestPin(RequestId, u64),
 
     // Server to client
     NewAlbum(Album),
-    ReturnAllAlbums(Vec<Album>),
-    ReturnPhotosInAlbum(Album, Vec<Photo>),
-    Thumbnail(Photo, Vec<u8>),
-    ReturnActiveAlbum(Option<Album>),
+    // The album added from a share link, to the controller that sent it. Everyone else is told with NewAlbum
+    ReturnNewAlbum(RequestId, Album),
+    ReturnAllAlbums(RequestId, Vec<Album>),
+    ReturnPhotosInAlbum(RequestId, Album, Vec<Photo>),
+    Thumbnail(RequestId, Photo, Vec<u8>),
+    // Follows the last thumbnail, and is the only reply when there are none
+    ThumbnailsEnd(RequestId),
+    // No id when announcing a change made by another controller
+    ReturnActiveAlbum(Option<RequestId>, Option<Album>),
+    ReturnState(RequestId, DisplayState),
+    // The display is shutting down and closes the connection after this
+    Goodbye,
+    // Broadcast on every change to the display's state
+    Event(DisplayEvent),
+    // A streamed photo: its size in bytes, (offset, bytes) chunks, then the SHA-256 digest of the whole
+    PhotoStart(RequestId, u64),
+    PhotoChunk(RequestId, u64, Vec<u8>),
+    PhotoEnd(RequestId, Vec<u8>),
+    // (request, reason) when the display could not answer a request
+    RequestFailed(RequestId, String),
+    // Every paired controller, the answer to listing them and to changing a role
+    ReturnControllers(RequestId, Vec<Controller>),
+    // (request, PIN, expiry in seconds since the epoch)
+    ReturnGuestPin(RequestId, String, u64),
+    // A message without a request id was refused, with the role it needs
+    PermissionDenied(Role),
 
-    // Dummy
-    TerminateThread,
+    // Liveness, answered by the connection itself and never passed to the application
+    Ping(u64),
+    Pong(u64),
 
     // Information to application
-    ConnectionMade
+    ConnectionMade,
+    ConnectionState(ConnectionState),
+    // The HTTP API stopped or never started, with the reason
+    ApiUnavailable(String)
+}
+
+/// Whether the display is moving through the photos of its album.
+#[derive(Serialize, Deserialize, Archive, serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum PlaybackState {
+    Stopped,
+    Playing,
+    Paused
+}
+
+/// Progress of adding an album from a share link.
+#[derive(Serialize, Deserialize, Archive, serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub enum SyncState {
+    Idle,
+    Running,
+    // What the last sync changed in its album
+    Finished(SyncReport),
+    Failed(String)
+}
+
+/// What a controller shows about the display. Sent whole on request, then kept current by events.
+#[derive(Serialize, Deserialize, Archive, serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct DisplayState {
+    pub album: Option<Album>,
+    pub photo: Option<Photo>,
+    pub playback: PlaybackState,
+    pub sync: SyncState,
+    pub authenticated: bool
+}
+
+/// A single change to the display's state.
+#[derive(Serialize, Deserialize, Archive, serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub enum DisplayEvent {
+    AlbumChanged(Option<Album>),
+    PhotoChanged(Option<Photo>),
+    PlaybackChanged(PlaybackState),
+    SyncChanged(SyncState),
+    AuthenticationChanged(bool)
+}
+
+impl DisplayState {
+    pub fn apply(&mut self, event: DisplayEvent) {
+        match event {
+            DisplayEvent::AlbumChanged(album) => self.album = album,
+            DisplayEvent::PhotoChanged(photo) => self.photo = photo,
+            DisplayEvent::PlaybackChanged(playback) => self.playback = playback,
+            DisplayEvent::SyncChanged(sync) => self.sync = sync,
+            DisplayEvent::AuthenticationChanged(authenticated) => self.authenticated = authenticated
+        }
+    }
+}
+
+/// Progress of the controller's connection to the display, reported to the application.
+#[derive(Serialize, Deserialize, Archive, serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Lost,
+    // The peer stopped answering pings
+    TimedOut,
+    // The display said goodbye before shutting down
+    Closed,
+    // (attempt, seconds until the attempt)
+    Retrying(u32, u64)
 }
 
 impl NetworkMessage {
+
+    /// Requests whose answers make up the state shown by a controller, and so are repeated after a reconnect.
+    /// Only cheap requests qualify; thumbnails stream the whole library and are left to the application to ask for again.
+    pub fn is_state_request(&self) -> bool {
+        matches!(self,
+            NetworkMessage::RequestAllAlbums(_)
+            | NetworkMessage::RequestPhotosInAlbum(_, _)
+            | NetworkMessage::RequestActiveAlbum(_)
+            | NetworkMessage::RequestState(_)
+        )
+    }
+
+    /// The request a reply answers, if any.
+    pub fn reply_to(&self) -> Option<RequestId> {
+        match self {
+            NetworkMessage::ReturnAllAlbums(id, _)
+            | NetworkMessage::ReturnPhotosInAlbum(id, _, _)
+            | NetworkMessage::Thumbnail(id, _, _)
+            | NetworkMessage::ThumbnailsEnd(id)
+            | NetworkMessage::ReturnActiveAlbum(Some(id), _)
+            | NetworkMessage::ReturnState(id, _)
+            | NetworkMessage::PhotoStart(id, _)
+            | NetworkMessage::PhotoChunk(id, _, _)
+            | NetworkMessage::PhotoEnd(id, _)
+            | NetworkMessage::RequestFailed(id, _)
+            | NetworkMessage::ReturnControllers(id, _)
+            | NetworkMessage::ReturnGuestPin(id, _, _)
+            | NetworkMessage::ReturnNewAlbum(id, _) => Some(*id),
+            _ => None
+        }
+    }
+
+    /// Replies that are followed by more replies to the same request.
+    pub fn is_partial_reply(&self) -> bool {
+        matches!(self, NetworkMessage::PhotoStart(_, _) | NetworkMessage::PhotoChunk(_, _, _))
+    }
+
+    /// The role a controller needs before the display acts on this message.
+    /// Replacing the account, adding albums and managing controllers is left to owners.
+    pub fn required_role(&self) -> Role {
+        match self {
+            NetworkMessage::TokenSet(_)
+            | NetworkMessage::Sharelink(_, _)
+            | NetworkMessage::RequestControllers(_)
+            | NetworkMessage::SetRole(_, _, _)
+            | NetworkMessage::RequestGuestPin(_, _) => Role::Owner,
+            _ => Role::Viewer
+        }
+    }
+
+    /// The id of a request, which a refusal of it must carry.
+    pub fn request_id(&self) -> Option<RequestId> {
+        match self {
+            NetworkMessage::RequestAllAlbums(id)
+            | NetworkMessage::RequestPhotosInAlbum(id, _)
+            | NetworkMessage::RequestThumbnails(id)
+            | NetworkMessage::RequestThumbnail(id, _, _)
+            | NetworkMessage::RequestPhoto(id, _, _)
+            | NetworkMessage::CancelTransfer(id)
+            | NetworkMessage::RequestActiveAlbum(id)
+            | NetworkMessage::RequestState(id)
+            | NetworkMessage::RequestControllers(id)
+            | NetworkMessage::SetRole(id, _, _)
+            | NetworkMessage::RequestGuestPin(id, _)
+            | NetworkMessage::Sharelink(id, _) => Some(*id),
+            _ => None
+        }
+    }
+
+    /// Long lists that shrink well when the connection's codec compresses.
+    pub fn is_compressible(&self) -> bool {
+        matches!(self, NetworkMessage::ReturnAllAlbums(_, _) | NetworkMessage::ReturnPhotosInAlbum(_, _, _) | NetworkMessage::ReturnControllers(_, _))
+    }
+
+    /// Large messages, and the end of a run of them, that may wait behind everything else queued on a connection.
+    pub fn is_bulk(&self) -> bool {
+        matches!(self, NetworkMessage::Thumbnail(_, _, _) | NetworkMessage::ThumbnailsEnd(_) | NetworkMessage::PhotoStart(_, _) | NetworkMessage::PhotoChunk(_, _, _) | NetworkMessage::PhotoEnd(_, _))
+    }
+
     pub fn to_bytes(&self) -> Res<Vec<u8>> {
         Ok(rkyv::to_bytes::<rkyv::rancor::Error>(self)?.into_vec())
     }
 
+    /// The archive is validated before anything is read from it. rkyv needs it aligned, which a slice out of a frame is not.
     pub fn from_bytes(bytes: &[u8]) -> Res<Self> {
-        Ok(rkyv::from_bytes::<Self, rkyv::rancor::Error>(bytes)?)
+        let mut aligned = AlignedVec::<16>::with_capacity(bytes.len());
+        aligned.extend_from_slice(bytes);
+        Ok(rkyv::from_bytes::<Self, rkyv::rancor::Error>(&aligned)?)
     }
 }
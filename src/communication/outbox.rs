@@ -0,0 +1,100 @@
+use async_channel::{Receiver, Sender, TrySendError, bounded};
+
+use crate::communication::NetworkMessage;
+use crate::error::ChannelError;
+
+/// Control messages queued on one connection before whoever queues them has to wait.
+pub const CONTROL_CAPACITY: usize = 256;
+
+/// Bulk messages queued on one connection before whoever queues them has to wait.
+/// Kept small so a slow peer holds up the photo being read rather than filling memory with it.
+pub const BULK_CAPACITY: usize = 8;
+
+/// Decides which queue of an outbox a message goes to.
+pub trait Priority {
+    fn is_bulk(&self) -> bool;
+}
+
+impl Priority for NetworkMessage {
+    fn is_bulk(&self) -> bool {
+        NetworkMessage::is_bulk(self)
+    }
+}
+
+impl<T> Priority for (T, NetworkMessage) {
+    fn is_bulk(&self) -> bool {
+        self.1.is_bulk()
+    }
+}
+
+/// Sending end of a pair of bounded queues, one for control traffic and one for bulk transfers.
+#[derive(Debug)]
+pub struct Outbox<T> {
+    control: Sender<T>,
+    bulk: Sender<T>
+}
+
+/// Receiving end of an outbox, which always hands out queued control traffic before bulk transfers.
+#[derive(Debug)]
+pub struct Inbox<T> {
+    pub control: Receiver<T>,
+    pub bulk: Receiver<T>
+}
+
+pub fn outbox<T>() -> (Outbox<T>, Inbox<T>) {
+    let (control_sender, control_receiver) = bounded(CONTROL_CAPACITY);
+    let (bulk_sender, bulk_receiver) = bounded(BULK_CAPACITY);
+
+    (
+        Outbox { control: control_sender, bulk: bulk_sender },
+        Inbox { control: control_receiver, bulk: bulk_receiver }
+    )
+}
+
+impl<T> Clone for Outbox<T> {
+    fn clone(&self) -> Self {
+        Self { control: self.control.clone(), bulk: self.bulk.clone() }
+    }
+}
+
+impl<T: Priority> Outbox<T> {
+
+    /// Queue a message, waiting while its queue is full.
+    pub async fn push(&self, message: T) -> Result<(), ChannelError> {
+        Ok(self.queue(&message).send(message).await?)
+    }
+
+    /// Queue a message, failing rather than waiting when its queue is full.
+    pub fn try_push(&self, message: T) -> Result<(), TrySendError<T>> {
+        self.queue(&message).try_send(message)
+    }
+
+    fn queue(&self, message: &T) -> &Sender<T> {
+        match message.is_bulk() {
+            true => &self.bulk,
+            false => &self.control
+        }
+    }
+}
+
+impl<T> Outbox<T> {
+
+    /// Stop accepting messages. The inbox still hands out what was queued before it runs dry.
+    pub fn close(&self) {
+        self.control.close();
+        self.bulk.close();
+    }
+}
+
+impl<T> Inbox<T> {
+
+    /// The next queued message, or `None` once the outbox is closed and everything queued has been handed out.
+    pub async fn next(&self) -> Option<T> {
+        tokio::select! {
+            biased;
+            Ok(message) = self.control.recv() => Some(message),
+            Ok(message) = self.bulk.recv() => Some(message),
+            else => None
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_channel::{Receiver, Sender, bounded};
+use tokio::time::timeout;
+
+use crate::communication::outbox::BULK_CAPACITY;
+use crate::communication::{NetworkMessage, RequestId};
+use crate::error::{ChannelError, Res};
+use crate::frontend::application::ApplicationError;
+
+/// Where the replies to each request go, and whether the request is answered by a series of replies.
+type Waiting = Arc<Mutex<HashMap<RequestId, (Sender<NetworkMessage>, bool)>>>;
+
+/// Requests waiting on their reply, shared between the side sending them and the side reading replies.
+#[derive(Clone, Debug, Default)]
+pub struct PendingRequests {
+    waiting: Waiting,
+    next_request: Arc<AtomicU64>
+}
+
+impl PendingRequests {
+
+    /// Send a request under a fresh id through `deliver`, and wait for the reply carrying that id.
+    /// Requests answered by several messages, such as thumbnails, resolve with the first.
+    pub async fn request<F: Future<Output = Res<()>>>(&self, request: impl FnOnce(RequestId) -> NetworkMessage, deliver: impl FnOnce(NetworkMessage) -> F, within: Duration) -> Res<NetworkMessage> {
+        let id = self.next_request.fetch_add(1, Ordering::Relaxed);
+        let (reply_sender, reply_receiver) = bounded(1);
+        self.waiting.lock().unwrap().insert(id, (reply_sender, false));
+
+        let reply = match deliver(request(id)).await {
+            Ok(()) => timeout(within, reply_receiver.recv()).await,
+            Err(e) => {
+                self.waiting.lock().unwrap().remove(&id);
+                return Err(e);
+            }
+        };
+
+        self.waiting.lock().unwrap().remove(&id);
+
+        match reply {
+            Ok(Ok(NetworkMessage::RequestFailed(_, reason))) => Err(ApplicationError::RequestFailed(id, reason).into()),
+            Ok(Ok(reply)) => Ok(reply),
+            Ok(Err(e)) => Err(ChannelError::from(e).into()),
+            Err(_) => Err(ApplicationError::RequestTimedOut(id).into())
+        }
+    }
+
+    /// Send a request answered by a series of replies, which arrive on the returned receiver up to and including the final one.
+    /// The caller must `forget` the request once it is done with it, and keep reading until then: replies wait for room on the receiver.
+    pub async fn subscribe<F: Future<Output = Res<()>>>(&self, request: impl FnOnce(RequestId) -> NetworkMessage, deliver: impl FnOnce(NetworkMessage) -> F) -> Res<(RequestId, Receiver<NetworkMessage>)> {
+        let id = self.next_request.fetch_add(1, Ordering::Relaxed);
+        let (reply_sender, reply_receiver) = bounded(BULK_CAPACITY);
+        self.waiting.lock().unwrap().insert(id, (reply_sender, true));
+
+        match deliver(request(id)).await {
+            Ok(()) => Ok((id, reply_receiver)),
+            Err(e) => {
+                self.forget(id);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn forget(&self, id: RequestId) {
+        self.waiting.lock().unwrap().remove(&id);
+    }
+
+    /// Hand a reply to the request waiting on it. Anything else, including replies nobody waits for any more, is given back.
+    pub async fn resolve(&self, message: NetworkMessage) -> Option<NetworkMessage> {
+        let waiting = message.reply_to().and_then(|id| {
+            let mut waiting = self.waiting.lock().unwrap();
+            match waiting.get(&id) {
+                Some((sender, true)) if message.is_partial_reply() => Some(sender.clone()),
+                Some(_) => waiting.remove(&id).map(|(sender, _)| sender),
+                None => None
+            }
+        });
+
+        match waiting {
+            Some(waiting) => waiting.send(message).await.err().map(|e| e.into_inner()),
+            None => Some(message)
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rkyv::{Archive, Deserialize, Serialize};
+
+/// What a paired controller is allowed to ask of the display.
+#[derive(Serialize, Deserialize, Archive, serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    // Re-authenticates, adds albums and manages the other controllers
+    Owner,
+    // Chooses albums and moves through photos
+    Viewer,
+    // A viewer whose access ends when the guest PIN it paired with expires
+    Guest
+}
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Owner => "owner",
+            Role::Viewer => "viewer",
+            Role::Guest => "guest"
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Role> {
+        [Role::Owner, Role::Viewer, Role::Guest].into_iter().find(|role| role.name() == name)
+    }
+
+    /// Whether this role grants everything `required` does. A guest can do what a viewer can.
+    pub fn satisfies(&self, required: Role) -> bool {
+        matches!((self, required), (Role::Owner, _) | (Role::Viewer | Role::Guest, Role::Viewer | Role::Guest))
+    }
+}
+
+/// The role of a controller, and when it stops counting for a guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Access {
+    pub role: Role,
+    // Seconds since the epoch
+    pub expires_at: Option<u64>
+}
+
+impl Access {
+    pub fn expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now())
+    }
+}
+
+/// A controller paired with the display, as listed to its owners.
+#[derive(Serialize, Deserialize, Archive, serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Controller {
+    pub controller_id: String,
+    pub role: Role,
+    pub paired_at: u64,
+    pub expires_at: Option<u64>,
+    pub connected: bool
+}
+
+impl Controller {
+    pub fn access(&self) -> Access {
+        Access { role: self.role, expires_at: self.expires_at }
+    }
+}
+
+/// Seconds since the epoch, the unit expiry times are kept in.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or_default()
+}
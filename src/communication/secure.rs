@@ -0,0 +1,416 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rand::{Rng, rng};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::hkdf::{HKDF_SHA256, KeyType, Salt};
+use ring::hmac::{self, HMAC_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use rusqlite_async::database::DataLink;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+
+use crate::communication::roles::{self, Access, Role};
+use crate::communication::spake2::{self, Side, Spake2};
+use crate::communication::transport::Transport;
+use crate::database::interface::{insert_controller, insert_controller_role, insert_pairing, select_controller, select_controller_key, select_controllers, select_pairing};
+use crate::error::Res;
+use crate::frontend::application::ApplicationError;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const KEY_LENGTH: usize = 32;
+
+const MODE_PAIR: u8 = 0;
+const MODE_RESUME: u8 = 1;
+
+// A display offers at most its pairing PIN and a guest PIN
+const MAX_CANDIDATES: u8 = 2;
+const TAG_LENGTH: usize = 32;
+
+#[derive(Clone, Debug)]
+pub enum SecureChannelError {
+    KeyAgreementFailed,
+    // The controller holds no pairing for this display and no PIN was supplied
+    PairingRequired,
+    // The display closed the connection instead of confirming the key, usually a wrong PIN
+    PairingRejected,
+    UnknownController,
+    // A guest whose access has run out tried to resume
+    AccessExpired,
+    InvalidConfirmation,
+    EncryptionFailed,
+    DecryptionFailed
+}
+
+/// Credentials a controller holds for one display, issued once the PIN has been confirmed.
+#[derive(Clone, Debug)]
+pub struct Pairing {
+    pub display_id: String,
+    pub controller_id: String,
+    pub key: Vec<u8>
+}
+
+/// A PIN an owner handed out, pairing a guest whose access ends at `expires_at` (seconds since the epoch).
+#[derive(Clone, Debug)]
+pub struct GuestPin {
+    pub pin: String,
+    pub expires_at: u64
+}
+
+/// State shared between the display server and its UI for accepting new controllers.
+#[derive(Clone)]
+pub struct PairingContext {
+    pub database: DataLink,
+    pub display_id: String,
+    pin: Arc<Mutex<String>>,
+    guest_pin: Arc<Mutex<Option<GuestPin>>>
+}
+
+impl PairingContext {
+    pub fn new(database: DataLink, display_id: String, pin: Arc<Mutex<String>>, guest_pin: Arc<Mutex<Option<GuestPin>>>) -> PairingContext {
+        rotate_pin(&pin);
+        PairingContext { database, display_id, pin, guest_pin }
+    }
+
+    /// The PINs a new controller may be pairing with, the guest PIN along with what it grants.
+    /// The pairing PIN is only good for a single attempt, successful or not, so it cannot be guessed online.
+    /// The guest PIN is left in place until `consume_guest_pin`, so a failed attempt does not use it up.
+    fn pins(&self) -> Vec<(String, Option<GuestPin>)> {
+        let mut pins = vec![(self.pin.lock().unwrap().clone(), None)];
+        rotate_pin(&self.pin);
+
+        if let Some(guest_pin) = self.guest_pin.lock().unwrap().clone() && guest_pin.expires_at > roles::now() {
+            pins.push((guest_pin.pin.clone(), Some(guest_pin)));
+        }
+
+        pins
+    }
+
+    /// Use up the guest PIN once a guest has proven it, failing if another guest got there first.
+    fn consume_guest_pin(&self, pin: &str) -> bool {
+        let mut guest_pin = self.guest_pin.lock().unwrap();
+        match guest_pin.as_ref().is_some_and(|guest_pin| guest_pin.pin == pin) {
+            true => { *guest_pin = None; true },
+            false => false
+        }
+    }
+}
+
+fn rotate_pin(pin: &Mutex<String>) {
+    let mut pin = pin.lock().unwrap();
+    *pin = random_pin();
+}
+
+pub fn random_pin() -> String {
+    format!("{:06}", rng().random_range(0..1_000_000))
+}
+
+/// Encrypts outgoing frames. Each direction has its own key so the counter nonces never collide.
+pub struct Sealer {
+    key: LessSafeKey,
+    counter: u64
+}
+
+/// Decrypts incoming frames, rejecting anything that was altered, replayed or reordered.
+pub struct Opener {
+    key: LessSafeKey,
+    counter: u64
+}
+
+impl Sealer {
+    pub fn seal(&mut self, mut plaintext: Vec<u8>) -> Res<Vec<u8>> {
+        let nonce = next_nonce(&mut self.counter);
+        self.key.seal_in_place_append_tag(nonce, Aad::empty(), &mut plaintext).map_err(|_| SecureChannelError::EncryptionFailed)?;
+        Ok(plaintext)
+    }
+}
+
+impl Opener {
+    pub fn open(&mut self, mut ciphertext: Vec<u8>) -> Res<Vec<u8>> {
+        let nonce = next_nonce(&mut self.counter);
+        let plaintext_length = self.key.open_in_place(nonce, Aad::empty(), &mut ciphertext).map_err(|_| SecureChannelError::DecryptionFailed)?.len();
+        ciphertext.truncate(plaintext_length);
+        Ok(ciphertext)
+    }
+}
+
+fn next_nonce(counter: &mut u64) -> Nonce {
+    let mut nonce = [0u8; aead::NONCE_LEN];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    *counter += 1;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+struct Length(usize);
+
+impl KeyType for Length {
+    fn len(&self) -> usize { self.0 }
+}
+
+/// Keys derived from one handshake: one per direction plus one for confirming the shared secret.
+struct SessionKeys {
+    client_to_server: [u8; KEY_LENGTH],
+    server_to_client: [u8; KEY_LENGTH],
+    confirmation: hmac::Key
+}
+
+impl SessionKeys {
+
+    /// The SPAKE2 secret provides forward secrecy and, through the PIN or pairing key it was computed with, authentication.
+    /// The PIN or pairing key is also the salt, binding the keys to it once more.
+    fn derive(shared_secret: &[u8], authentication_secret: &[u8], transcript: &[u8]) -> Res<SessionKeys> {
+        let prk = Salt::new(HKDF_SHA256, authentication_secret).extract(shared_secret);
+        let info = [transcript];
+        let mut material = [0u8; KEY_LENGTH * 3];
+        prk.expand(&info, Length(material.len()))
+            .and_then(|okm| okm.fill(&mut material))
+            .map_err(|_| SecureChannelError::KeyAgreementFailed)?;
+
+        let mut client_to_server = [0u8; KEY_LENGTH];
+        let mut server_to_client = [0u8; KEY_LENGTH];
+        client_to_server.copy_from_slice(&material[..KEY_LENGTH]);
+        server_to_client.copy_from_slice(&material[KEY_LENGTH..KEY_LENGTH * 2]);
+
+        Ok(SessionKeys {
+            client_to_server,
+            server_to_client,
+            confirmation: hmac::Key::new(HMAC_SHA256, &material[KEY_LENGTH * 2..])
+        })
+    }
+
+    fn confirm(&self, role: &[u8], transcript: &[u8]) -> Vec<u8> {
+        let mut context = hmac::Context::with_key(&self.confirmation);
+        context.update(role);
+        context.update(transcript);
+        context.sign().as_ref().to_vec()
+    }
+
+    fn verify(&self, role: &[u8], transcript: &[u8], tag: &[u8]) -> Res<()> {
+        let mut message = role.to_vec();
+        message.extend_from_slice(transcript);
+        hmac::verify(&self.confirmation, &message, tag).map_err(|_| SecureChannelError::InvalidConfirmation)?;
+        Ok(())
+    }
+
+    fn client(self) -> Res<(Sealer, Opener)> {
+        Ok((sealer(&self.client_to_server)?, opener(&self.server_to_client)?))
+    }
+
+    fn server(self) -> Res<(Sealer, Opener)> {
+        Ok((sealer(&self.server_to_client)?, opener(&self.client_to_server)?))
+    }
+}
+
+fn less_safe_key(key: &[u8]) -> Res<LessSafeKey> {
+    Ok(LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key).map_err(|_| SecureChannelError::KeyAgreementFailed)?))
+}
+
+fn sealer(key: &[u8]) -> Res<Sealer> {
+    Ok(Sealer { key: less_safe_key(key)?, counter: 0 })
+}
+
+fn opener(key: &[u8]) -> Res<Opener> {
+    Ok(Opener { key: less_safe_key(key)?, counter: 0 })
+}
+
+fn random_bytes<const N: usize>() -> Res<[u8; N]> {
+    let mut bytes = [0u8; N];
+    SystemRandom::new().fill(&mut bytes).map_err(|_| SecureChannelError::KeyAgreementFailed)?;
+    Ok(bytes)
+}
+
+async fn write_short(stream: &mut impl Transport, bytes: &[u8]) -> Res<()> {
+    let length = u8::try_from(bytes.len()).map_err(|_| ApplicationError::FieldTooLong(bytes.len()))?;
+    stream.write_u8(length).await?;
+    stream.write_all(bytes).await?;
+    Ok(())
+}
+
+async fn read_short(stream: &mut impl Transport) -> Res<Vec<u8>> {
+    let length = stream.read_u8().await?;
+    let mut bytes = vec![0u8; length as usize];
+    stream.read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
+
+async fn read_exact<const N: usize>(stream: &mut impl Transport) -> Res<[u8; N]> {
+    let mut bytes = [0u8; N];
+    stream.read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
+
+/// Display side of the secure handshake. Returns the channel halves, the identifier of the controller and its access.
+///
+/// S -> C: display id
+/// C -> S: mode (pair with PIN / resume with pairing key), [controller id], SPAKE2 message
+/// S -> C: candidate count, a SPAKE2 message per PIN or key the display accepts
+/// S -> C: a confirmation tag per candidate
+/// C -> S: confirmation tag for the candidate that matched
+/// S -> C: (pair only) encrypted controller id and pairing key
+///
+/// The display confirms first, so a controller never sends a tag derived from its PIN to a peer that has not proven it knows it.
+pub async fn accept(stream: &mut impl Transport, context: &PairingContext) -> Res<(Sealer, Opener, String, Access)> {
+    timeout(HANDSHAKE_TIMEOUT, accept_inner(stream, context)).await.map_err(|_| ApplicationError::HandshakeTimeout)?
+}
+
+async fn accept_inner(stream: &mut impl Transport, context: &PairingContext) -> Res<(Sealer, Opener, String, Access)> {
+    write_short(stream, context.display_id.as_bytes()).await?;
+
+    let mode = stream.read_u8().await?;
+    let resumed_controller = match mode {
+        MODE_RESUME => Some(String::from_utf8_lossy(&read_short(stream).await?).to_string()),
+        _ => None
+    };
+    let client_message = read_exact::<{ spake2::ELEMENT_LENGTH }>(stream).await?;
+
+    // A guest whose access has run out is turned away before any keys are exchanged
+    let resumed_access = match resumed_controller.as_ref() {
+        Some(controller_id) => Some(select_controller(context.database.clone(), controller_id.clone()).await?.ok_or(SecureChannelError::UnknownController)?.access()),
+        None => None
+    };
+    if resumed_access.is_some_and(|access| access.expired()) {
+        return Err(SecureChannelError::AccessExpired.into());
+    }
+
+    // A new controller may hold the pairing PIN or a guest PIN, whichever its confirmation tag proves
+    let candidates = match resumed_controller.as_ref() {
+        Some(controller_id) => vec![(select_controller_key(context.database.clone(), controller_id.clone()).await?.ok_or(SecureChannelError::UnknownController)?, None)],
+        None => context.pins().into_iter().map(|(pin, guest)| (pin.into_bytes(), guest)).collect()
+    };
+
+    let prefix = [context.display_id.as_bytes(), &[mode], resumed_controller.as_deref().unwrap_or_default().as_bytes(), &client_message].concat();
+    let mut offered = Vec::with_capacity(candidates.len());
+    stream.write_u8(candidates.len() as u8).await?;
+
+    for (index, (authentication_secret, guest)) in candidates.into_iter().enumerate() {
+        let spake = Spake2::start(Side::Display, &authentication_secret)?;
+        let transcript = [&prefix, &[index as u8][..], spake.message()].concat();
+        let keys = SessionKeys::derive(&spake.finish(&client_message)?, &authentication_secret, &transcript)?;
+
+        stream.write_all(spake.message()).await?;
+        offered.push((keys, transcript, guest));
+    }
+
+    for (keys, transcript, _) in &offered {
+        stream.write_all(&keys.confirm(b"display", transcript)).await?;
+    }
+
+    // Closing the connection is how a wrong PIN or key is reported to the controller, which has usually noticed already
+    let tag = read_exact::<TAG_LENGTH>(stream).await?;
+    let (keys, _, guest) = offered
+        .into_iter()
+        .find(|(keys, transcript, _)| keys.verify(b"controller", transcript, &tag).is_ok())
+        .ok_or(SecureChannelError::InvalidConfirmation)?;
+
+    let access = match (resumed_access, guest) {
+        (Some(access), _) => access,
+        (None, Some(guest)) => match context.consume_guest_pin(&guest.pin) {
+            true => Access { role: Role::Guest, expires_at: Some(guest.expires_at) },
+            false => return Err(SecureChannelError::AccessExpired.into())
+        },
+        // Whoever pairs first owns the display
+        (None, None) => match select_controllers(context.database.clone()).await?.iter().any(|controller| controller.role == Role::Owner) {
+            true => Access { role: Role::Viewer, expires_at: None },
+            false => Access { role: Role::Owner, expires_at: None }
+        }
+    };
+
+    if access.expired() {
+        return Err(SecureChannelError::AccessExpired.into());
+    }
+
+    let (mut sealer, opener) = keys.server()?;
+
+    let controller_id = match resumed_controller {
+        Some(controller_id) => controller_id,
+        None => {
+            let controller_id = BASE64_URL_SAFE_NO_PAD.encode(random_bytes::<16>()?);
+            let pairing_key = random_bytes::<KEY_LENGTH>()?.to_vec();
+
+            // Stored before the controller hears of it, so it can resume as soon as it has the key
+            insert_controller(context.database.clone(), controller_id.clone(), pairing_key.clone()).await?;
+            insert_controller_role(context.database.clone(), controller_id.clone(), access).await?;
+
+            let credentials = sealer.seal([controller_id.as_bytes(), &pairing_key].concat())?;
+            stream.write_u32(credentials.len() as u32).await?;
+            stream.write_all(&credentials).await?;
+            controller_id
+        }
+    };
+
+    Ok((sealer, opener, controller_id, access))
+}
+
+/// Controller side of the secure handshake. Uses a stored pairing for the display if one exists, otherwise the PIN.
+/// A fresh pairing is saved to the database before returning, along with the id of the display.
+pub async fn connect(stream: &mut impl Transport, database: DataLink, pin: Option<String>) -> Res<(Sealer, Opener, String)> {
+    timeout(HANDSHAKE_TIMEOUT, connect_inner(stream, database, pin)).await.map_err(|_| ApplicationError::HandshakeTimeout)?
+}
+
+async fn connect_inner(stream: &mut impl Transport, database: DataLink, pin: Option<String>) -> Res<(Sealer, Opener, String)> {
+    let display_id = String::from_utf8_lossy(&read_short(stream).await?).to_string();
+
+    let (mode, controller_id, authentication_secret) = match (select_pairing(database.clone(), display_id.clone()).await?, pin) {
+        (Some(pairing), _) => (MODE_RESUME, Some(pairing.controller_id), pairing.key),
+        (None, Some(pin)) => (MODE_PAIR, None, pin.trim().as_bytes().to_vec()),
+        (None, None) => return Err(SecureChannelError::PairingRequired.into())
+    };
+
+    let spake = Spake2::start(Side::Controller, &authentication_secret)?;
+    stream.write_u8(mode).await?;
+    if let Some(controller_id) = controller_id.as_ref() {
+        write_short(stream, controller_id.as_bytes()).await?;
+    }
+    stream.write_all(spake.message()).await?;
+
+    let count = stream.read_u8().await.map_err(|_| SecureChannelError::PairingRejected)?;
+    if count == 0 || count > MAX_CANDIDATES {
+        return Err(SecureChannelError::KeyAgreementFailed.into());
+    }
+
+    let mut server_messages = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        server_messages.push(read_exact::<{ spake2::ELEMENT_LENGTH }>(stream).await?);
+    }
+
+    let mut tags = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        tags.push(read_exact::<TAG_LENGTH>(stream).await?);
+    }
+
+    let prefix = [display_id.as_bytes(), &[mode], controller_id.as_deref().unwrap_or_default().as_bytes(), spake.message()].concat();
+
+    // Only a display that knows the PIN or key can produce a tag that verifies here
+    let mut confirmed = None;
+    for (index, (server_message, tag)) in server_messages.iter().zip(&tags).enumerate() {
+        let transcript = [&prefix, &[index as u8][..], server_message].concat();
+        let keys = SessionKeys::derive(&spake.finish(server_message)?, &authentication_secret, &transcript)?;
+        if keys.verify(b"display", &transcript, tag).is_ok() {
+            confirmed = Some((keys, transcript));
+            break;
+        }
+    }
+    let (keys, transcript) = confirmed.ok_or(SecureChannelError::PairingRejected)?;
+
+    stream.write_all(&keys.confirm(b"controller", &transcript)).await?;
+
+    let (sealer, mut opener) = keys.client()?;
+
+    if mode == MODE_PAIR {
+        let length = stream.read_u32().await?;
+        let mut credentials = vec![0u8; length as usize];
+        stream.read_exact(&mut credentials).await?;
+        let credentials = opener.open(credentials)?;
+
+        // The pairing key occupies the final KEY_LENGTH bytes, the controller id everything before it
+        let (controller_id, key) = credentials.split_at(credentials.len().saturating_sub(KEY_LENGTH));
+        insert_pairing(database, Pairing {
+            display_id: display_id.clone(),
+            controller_id: String::from_utf8_lossy(controller_id).to_string(),
+            key: key.to_vec()
+        }).await?;
+    }
+
+    Ok((sealer, opener, display_id))
+}
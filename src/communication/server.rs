@@ -1,128 +1,839 @@
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::tcp::{OwnedReadHalf, OwnedWriteHalf}, task::{JoinHandle, spawn}};
+use tokio::{io::{AsyncRead, AsyncWrite, AsyncWriteExt, split}, net::TcpStream, task::{JoinHandle, JoinSet, spawn}};
 use tokio::net::TcpListener;
-use std::net::{IpAddr, Ipv4Addr};
-use async_channel::{Receiver, Sender, unbounded};
+use tokio::time::timeout;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use async_channel::{Receiver, Sender, TrySendError, bounded, unbounded};
+use futures_util::{Stream, StreamExt, future::join_all};
 use std::sync::{Arc, Mutex};
 
-pub const IDENTIFIER: &str = "reflection";
-pub const PORT: u16 = 7878;
+use rusqlite_async::database::DataLink;
 
-use crate::{communication::NetworkMessage, error::{ChannelError, Res}, frontend::application::ApplicationError, util::channel::send};
+use crate::{communication::{ConnectionState, NetworkMessage, codec::{self, Codec, Encoding}, discovery::{self, Announcement, DisplayStatus, Group}, handshake, heartbeat::{self, Heartbeat}, http::{self, API_PEER}, outbox::{self, CONTROL_CAPACITY, Inbox, Outbox}, roles::{self, Access, Controller, Role}, secure::{self, GuestPin, Opener, PairingContext, SecureChannelError, Sealer}, transport::Transport}, database::interface, error::{Error, Res}, frontend::application::ApplicationError, util::channel::send};
+
+/// How long shutting down waits for controllers to be told goodbye before dropping them.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long bulk traffic waits for room in a controller's queue before the controller is disconnected for not reading.
+const BULK_STALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest a guest PIN may grant access for.
+const MAX_GUEST_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Unique (per server lifetime) identifier of a connected controller.
+pub type PeerId = usize;
+
+/// Where an outgoing message should be delivered.
+#[derive(Clone, Copy, Debug)]
+pub enum Recipient {
+    // The reply to a request, sent only to the controller that asked
+    Peer(PeerId),
+    // State changes that every controller should see
+    All
+}
+
+/// Why a controller's message was not passed on to the application.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Refusal {
+    // The controller has already been disconnected
+    Gone,
+    // A guest whose access has run out
+    Expired(Role),
+    // The message needs a role the controller does not have
+    Forbidden(Role)
+}
+
+impl Refusal {
+    /// What the controller is told: a refused request is failed, anything else is answered with the role it needs.
+    fn reply(self, message: &NetworkMessage) -> Option<NetworkMessage> {
+        let (reason, required) = match self {
+            Refusal::Gone => return None,
+            Refusal::Expired(required) => (String::from("Guest access has expired"), required),
+            Refusal::Forbidden(required) => (format!("Only a controller with the {} role may do this", required.name()), required)
+        };
+
+        Some(match message.request_id() {
+            Some(id) => NetworkMessage::RequestFailed(id, reason),
+            None => NetworkMessage::PermissionDenied(required)
+        })
+    }
+}
+
+/// Options for the display's LAN service.
+#[derive(Clone, Debug, Default)]
+pub struct ServerConfig {
+    pub heartbeat: Heartbeat,
+    // Port and identifier shared with the controllers meant to find this display
+    pub group: Group,
+    // Listen only on this address, which may be IPv6, rather than on every IPv4 interface
+    pub bind: Option<IpAddr>,
+    // Replace the stored display id / name when given
+    pub display_id: Option<String>,
+    pub name: Option<String>,
+    // Serve the HTTP API on this address when given
+    pub api: Option<SocketAddr>,
+    // Ask every controller to use this wire encoding, such as JSON while inspecting traffic
+    pub codec: Option<Encoding>,
+    // Refuse frames larger than this many bytes from controllers, codec::DEFAULT_MAX_FRAME when not given
+    pub max_frame: Option<usize>,
+    // Serve only transports handed to `Server::attach`, without listening or answering discovery
+    pub offline: bool
+}
+
+struct Peer {
+    address: SocketAddr,
+    controller_id: String,
+    // Checked against every message the controller sends, and changed by its owners while it is connected
+    access: Access,
+    outbox: Outbox<NetworkMessage>,
+    // Disconnects a peer that stopped reading what is sent to it
+    evict: Sender<()>
+}
+
+type Connections = Arc<Mutex<HashMap<PeerId, Peer>>>;
+
+/// A connected transport waiting to be served, with the address reported for it.
+type Attached = (Box<dyn Transport>, SocketAddr);
+
+/// State the server thread shares with the application: the pairing PIN, the guest PIN, the advertised status and the API token.
+type Shared = (Arc<Mutex<String>>, Arc<Mutex<Option<GuestPin>>>, Arc<Mutex<DisplayStatus>>, Arc<Mutex<Option<String>>>);
 
 pub struct Server {
-    thread: JoinHandle<Res<()>>,
-    sender: Sender<NetworkMessage>,
-    active_connection: Arc<Mutex<Option<IpAddr>>>
+    // Taken when shutting down
+    thread: Mutex<Option<JoinHandle<Res<()>>>>,
+    stop: Sender<()>,
+    sender: Outbox<(Recipient, NetworkMessage)>,
+    attached: Sender<Attached>,
+    connections: Connections,
+    database: DataLink,
+    pin: Arc<Mutex<String>>,
+    guest_pin: Arc<Mutex<Option<GuestPin>>>,
+    status: Arc<Mutex<DisplayStatus>>,
+    api_token: Arc<Mutex<Option<String>>>
 }
 
 impl Server {
 
 
-    pub fn spawn() -> (Self, Receiver<NetworkMessage>) {
+    pub fn spawn(database: DataLink, config: ServerConfig) -> (Self, Receiver<(PeerId, NetworkMessage)>) {
 
-        let (send_to_foreign_sender, send_to_foreign_receiver) = unbounded();
-        let (recv_from_foreign_sender, recv_from_foreign_receiver) = unbounded();
-        let send_to_foreign_sender_clone = send_to_foreign_sender.clone();
+        let (send_to_foreign_sender, send_to_foreign_receiver) = outbox::outbox();
+        let (recv_from_foreign_sender, recv_from_foreign_receiver) = bounded(CONTROL_CAPACITY);
+        let (attached_sender, attached_receiver) = unbounded();
+        let (stop_sender, stop_receiver) = bounded(1);
 
-        let active_connection = Arc::new(Mutex::new(None));
-        let active_connection_clone = active_connection.clone();
+        let connections = Arc::new(Mutex::new(HashMap::new()));
+        let connections_clone = connections.clone();
+        let pin = Arc::new(Mutex::new(String::new()));
+        let pin_clone = pin.clone();
+        let guest_pin = Arc::new(Mutex::new(None));
+        let guest_pin_clone = guest_pin.clone();
+        let status = Arc::new(Mutex::new(DisplayStatus::default()));
+        let status_clone = status.clone();
+        let api_token = Arc::new(Mutex::new(None));
+        let api_token_clone = api_token.clone();
 
         (
             Self {
-                thread: spawn(Self::run(recv_from_foreign_sender, send_to_foreign_receiver, send_to_foreign_sender_clone, active_connection_clone)),
+                thread: Mutex::new(Some(spawn(Self::run(recv_from_foreign_sender, (send_to_foreign_receiver, attached_receiver, stop_receiver), connections_clone, database.clone(), (pin_clone, guest_pin_clone, status_clone, api_token_clone), config)))),
+                stop: stop_sender,
                 sender: send_to_foreign_sender,
-                active_connection
+                attached: attached_sender,
+                connections,
+                database,
+                pin,
+                guest_pin,
+                status,
+                api_token
             },
             recv_from_foreign_receiver
         )
     }
 
-    async fn run(output: Sender<NetworkMessage>, input: Receiver<NetworkMessage>, input_sender: Sender<NetworkMessage>, active_connection: Arc<Mutex<Option<IpAddr>>>) -> Res<()> {
+    async fn run(
+        output: Sender<(PeerId, NetworkMessage)>,
+        (input, attached, stop): (Inbox<(Recipient, NetworkMessage)>, Receiver<Attached>, Receiver<()>),
+        connections: Connections,
+        database: DataLink,
+        (pin, guest_pin, status, api_token): Shared,
+        config: ServerConfig
+    ) -> Res<()> {
+
+        let display_id = discovery::display_id(database.clone(), config.display_id).await?;
+        let name = discovery::display_name(database.clone(), config.name).await?;
+        let pairing_context = PairingContext::new(database.clone(), display_id.clone(), pin, guest_pin);
+        let heartbeat = config.heartbeat;
+        let preferred = config.codec;
+        let max_frame = config.max_frame.unwrap_or(codec::DEFAULT_MAX_FRAME);
+        let bind = config.bind.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let port = config.group.port;
+
+        let listener = match config.offline {
+            true => None,
+            false => Some(TcpListener::bind((bind, port)).await?)
+        };
+
+        let announce_connections = connections.clone();
+        let announce = move || Announcement {
+            display_id: display_id.clone(),
+            name: name.clone(),
+            port,
+            status: DisplayStatus {
+                controllers: announce_connections.lock().unwrap().len(),
+                ..status.lock().unwrap().clone()
+            }
+        };
+        let beacon = (!config.offline).then(|| spawn(discovery::beacon(config.group.clone(), bind, announce.clone())));
+        let advertisement = (!config.offline).then(|| spawn(discovery::advertise(config.group, bind, announce.clone())));
+
+        let (api_sender, api_receiver) = bounded(CONTROL_CAPACITY);
+        let api = match config.api {
+            Some(address) => {
+                let token = http::api_token(database).await?;
+                *api_token.lock().unwrap() = Some(token.clone());
+
+                let output = output.clone();
+                Some(spawn(async move {
+                    // Without the API there is no token worth showing, only the reason to report
+                    if let Err(e) = http::serve(address, token, output.clone(), api_receiver, announce).await {
+                        *api_token.lock().unwrap() = None;
+                        let _ = send((API_PEER, NetworkMessage::ApiUnavailable(format!("{e:?}"))), &output).await;
+                    }
+                }))
+            },
+            None => None
+        };
+
+        // Separate routers, so bulk traffic waiting on a slow peer never holds up control messages to anyone
+        let control_router = spawn(Self::route(input.control, connections.clone(), api_sender.clone()));
+        let bulk_router = spawn(Self::route(input.bulk, connections.clone(), api_sender));
+        let mut next_peer_id: PeerId = 0;
+        let mut serving = JoinSet::new();
+
+        loop {
+            let (transport, addr): Attached = tokio::select! {
+                accepted = Self::accept(listener.as_ref()) => match accepted {
+                    Ok((tcp_stream, addr)) => (Box::new(tcp_stream), addr),
+                    Err(_) => break
+                },
+                attached = attached.recv() => match attached {
+                    Ok(attached) => attached,
+                    Err(_) => break
+                },
+                _ = stop.recv() => break,
+                // Forget connections that have ended
+                Some(_) = serving.join_next() => continue
+            };
+
+            // Each controller is served independently so that one slow or dead peer cannot hold up the others
+            serving.spawn(Self::serve(transport, addr, next_peer_id, output.clone(), connections.clone(), pairing_context.clone(), (heartbeat, preferred, max_frame)));
+            next_peer_id += 1;
+        }
 
-        let listener = TcpListener::bind((IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), PORT)).await?;
-        let udp = udp_discovery::server::Server::spawn(IDENTIFIER, PORT).await;
+        // Stop accepting, then tell every controller the display is going away and let their queues flush
+        drop(listener);
+        for peer in connections.lock().unwrap().values() {
+            let _ = peer.outbox.try_push(NetworkMessage::Goodbye);
+            let _ = peer.evict.try_send(());
+        }
+        if timeout(SHUTDOWN_TIMEOUT, async { while serving.join_next().await.is_some() {} }).await.is_err() {
+            serving.abort_all();
+        }
+
+        control_router.abort();
+        bulk_router.abort();
+        if let Some(api) = api {
+            api.abort();
+        }
+        if let Some(beacon) = beacon {
+            beacon.abort();
+            let _ = beacon.await;
+        }
+        if let Some(advertisement) = advertisement {
+            advertisement.abort();
+            let _ = advertisement.await;
+        }
+        Ok(())
+    }
 
-        while let Ok((tcp_stream, addr)) = listener.accept().await {
+    /// Stop accepting connections, say goodbye to every controller and stop answering discovery.
+    /// The returned future finishes with the server thread, and only the first call waits for it.
+    pub fn shutdown(&self) -> impl Future<Output = Res<()>> + Send + 'static {
+        let _ = self.stop.try_send(());
+        let thread = self.thread.lock().unwrap().take();
 
-            {
-                let mut active_connection = active_connection.lock().unwrap();
-                active_connection.replace(addr.ip());
+        async move {
+            match thread {
+                Some(thread) => thread.await?,
+                None => Ok(())
             }
+        }
+    }
+
+    /// Wait for the next TCP connection, or forever when not listening.
+    async fn accept(listener: Option<&TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+        match listener {
+            Some(listener) => listener.accept().await,
+            None => std::future::pending().await
+        }
+    }
 
-            // Clones
-            let (read_half, write_half) = tcp_stream.into_split();
-            let output_clone = output.clone();
-            let input_clone = input.clone();
+    /// Serve a controller over an already connected transport, as if it had connected from `address`.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub async fn attach(&self, transport: impl Transport, address: SocketAddr) -> Res<()> {
+        send((Box::new(transport) as Box<dyn Transport>, address), &self.attached).await?;
+        Ok(())
+    }
 
-            let recv_thread = spawn(Self::recv(read_half, output_clone));
-            let send_thread = spawn(Self::send(write_half, input_clone));
+    /// Deliver outgoing messages to the outbox of each addressed peer.
+    /// Replies to the HTTP API go to `api` rather than to a controller.
+    ///
+    /// Bulk messages wait for room in each addressed peer's queue at once, which holds up whoever is producing them.
+    /// A peer whose bulk queue stays full for BULK_STALL_TIMEOUT, or whose control queue is full,
+    /// has stopped reading altogether and is disconnected rather than waited on.
+    async fn route(input: Receiver<(Recipient, NetworkMessage)>, connections: Connections, api: Sender<NetworkMessage>) {
+        while let Ok((recipient, message)) = input.recv().await {
+            if let Recipient::Peer(API_PEER) = recipient {
+                let _ = api.send(message).await;
+                continue;
+            }
 
-            let _ = recv_thread.await;
+            let peers: Vec<(PeerId, Outbox<NetworkMessage>, Sender<()>)> = {
+                let connections = connections.lock().unwrap();
+                connections
+                    .iter()
+                    .filter(|(peer_id, _)| match recipient {
+                        Recipient::Peer(wanted) => **peer_id == wanted,
+                        Recipient::All => true
+                    })
+                    .map(|(peer_id, peer)| (*peer_id, peer.outbox.clone(), peer.evict.clone()))
+                    .collect()
+            };
+
+            // A peer that disconnected in the meantime is not an error for the others
+            if message.is_bulk() {
+                join_all(peers.into_iter().map(|(peer_id, outbox, evict)| {
+                    let message = message.clone();
+                    async move {
+                        if timeout(BULK_STALL_TIMEOUT, outbox.push(message)).await.is_err() {
+                            eprintln!("Controller {peer_id} is not keeping up, disconnecting it");
+                            let _ = evict.try_send(());
+                        }
+                    }
+                })).await;
+                continue;
+            }
 
-            // Interupt send thread
-            input_sender.send_blocking(NetworkMessage::TerminateThread).map_err(ChannelError::from)?;
-            let _ = send_thread.await;
+            for (peer_id, outbox, evict) in peers {
+                if let Err(TrySendError::Full(_)) = outbox.try_push(message.clone()) {
+                    eprintln!("Controller {peer_id} is not keeping up, disconnecting it");
+                    let _ = evict.try_send(());
+                }
+            }
+        }
+    }
 
-            // Clear channel
-            while input.try_recv().is_ok() {}
+    /// Handle the lifetime of a single controller connection.
+    async fn serve(mut transport: Box<dyn Transport>, addr: SocketAddr, peer_id: PeerId, output: Sender<(PeerId, NetworkMessage)>, connections: Connections, pairing_context: PairingContext, (heartbeat, preferred, max_frame): (Heartbeat, Option<Encoding>, usize)) -> Res<()> {
 
-            // Reset active connection
-            {
-                let mut active_connection = active_connection.lock().unwrap();
-                _ = active_connection.take();
+        // Refuse peers that are not speaking our protocol before any frames are exchanged
+        let codec = match handshake::perform(&mut transport, preferred).await {
+            Ok(codec) => Codec { max_frame, ..codec },
+            Err(error) => {
+                eprintln!("Handshake with {addr} failed: {error:?}");
+                return Err(error);
+            }
+        };
+
+        // Only paired controllers (or one holding the current PIN) get any further
+        let (sealer, opener, controller_id, access) = match secure::accept(&mut transport, &pairing_context).await {
+            Ok(accepted) => accepted,
+            Err(error) => {
+                eprintln!("Pairing with {addr} failed: {error:?}");
+                return Err(error);
             }
+        };
+
+        let (peer_outbox, peer_inbox) = outbox::outbox();
+        let (evict_sender, evict_receiver) = bounded(1);
+
+        {
+            let mut connections = connections.lock().unwrap();
+            connections.insert(peer_id, Peer { address: addr, controller_id, access, outbox: peer_outbox.clone(), evict: evict_sender });
+        }
+
+        let (read_half, write_half) = split(transport);
+
+        send((peer_id, NetworkMessage::ConnectionState(ConnectionState::Connected)), &output).await?;
+
+        let admitting = connections.clone();
+        let mut recv_thread = spawn(Self::recv(read_half, opener, codec, output.clone(), move |nm| match Self::admit(&admitting, peer_id, &nm) {
+            Ok(()) => Ok((peer_id, nm)),
+            Err(refusal) => Err(refusal.reply(&nm).map(Box::new))
+        }, heartbeat, peer_outbox.clone()));
+        let mut send_thread = spawn(Self::send(write_half, sealer, codec, peer_inbox));
+        let ping_thread = spawn(heartbeat::pinger(peer_outbox.clone(), heartbeat));
+
+        let state = tokio::select! {
+            result = &mut recv_thread => match result {
+                Ok(Err(Error::ApplicationError(error))) if matches!(error.as_ref(), ApplicationError::PeerTimedOut) => ConnectionState::TimedOut,
+                Ok(Err(Error::ApplicationError(error))) if matches!(error.as_ref(), ApplicationError::MalformedFrame | ApplicationError::FrameTooLarge(_) | ApplicationError::FrameCorrupted) => {
+                    eprintln!("Dropping controller {addr} after a bad frame: {error:?}");
+                    ConnectionState::Lost
+                },
+                // Intact on the wire but not sealed with the session key, which is as bad a frame as any
+                Ok(Err(Error::SecureChannelError(error))) if matches!(error.as_ref(), SecureChannelError::DecryptionFailed) => {
+                    eprintln!("Dropping controller {addr} after a bad frame: {error:?}");
+                    ConnectionState::Lost
+                },
+                _ => ConnectionState::Lost
+            },
+            _ = evict_receiver.recv() => ConnectionState::Lost
+        };
+        recv_thread.abort();
+        ping_thread.abort();
+
+        // Forget the peer before interrupting the send thread so nothing else is routed to it
+        {
+            let mut connections = connections.lock().unwrap();
+            connections.remove(&peer_id);
+        }
+
+        send((peer_id, NetworkMessage::ConnectionState(state)), &output).await?;
+
+        // Let the send thread flush what is queued, unless the peer has stopped reading
+        peer_outbox.close();
+        if timeout(heartbeat.timeout, &mut send_thread).await.is_err() {
+            send_thread.abort();
         }
 
-        udp.stop();
-        let _ = udp.wait().await;
         Ok(())
     }
 
-    pub async fn recv(mut client: OwnedReadHalf, output: Sender<NetworkMessage>) -> Res<()> {
-        let mut size_buf = vec![0u8; 4];
+    /// Whether the controller's role allows a message to be passed on. A guest whose access has run out is refused and disconnected.
+    fn admit(connections: &Connections, peer_id: PeerId, message: &NetworkMessage) -> Result<(), Refusal> {
+        let connections = connections.lock().unwrap();
+        let peer = connections.get(&peer_id).ok_or(Refusal::Gone)?;
+        let required = message.required_role();
+
+        if peer.access.expired() {
+            let _ = peer.evict.try_send(());
+            Err(Refusal::Expired(required))
+        } else if !peer.access.role.satisfies(required) {
+            Err(Refusal::Forbidden(required))
+        } else {
+            Ok(())
+        }
+    }
 
+    /// Read frames until the connection closes, the peer says goodbye or is silent for longer than the heartbeat timeout.
+    /// Pings are answered through `replies`, the send queue of the same connection. Only a goodbye ends it without an error.
+    /// `tag` prepares each message for `output`, or refuses it with an optional reply to the peer.
+    pub async fn recv<T>(mut client: impl AsyncRead + Unpin, mut opener: Opener, codec: Codec, output: Sender<T>, tag: impl Fn(NetworkMessage) -> Result<T, Option<Box<NetworkMessage>>>, heartbeat: Heartbeat, replies: Outbox<NetworkMessage>) -> Res<()> {
         loop {
-            client.read_exact(&mut size_buf).await?;
-            let packet_size = u32::from_be_bytes(size_buf.clone().try_into().map_err(|_| ApplicationError::EndianFailure)?);
-            let mut buf = vec![0u8; packet_size as usize];
-            client.read_exact(&mut buf).await?;
+            let buf = timeout(heartbeat.timeout, codec.read_frame(&mut client)).await.map_err(|_| ApplicationError::PeerTimedOut)??;
+
+            match codec.decode(&opener.open(buf)?)? {
+                NetworkMessage::Ping(sequence) => replies.push(NetworkMessage::Pong(sequence)).await?,
+                // Receiving anything at all proves liveness, so a pong needs no further handling
+                NetworkMessage::Pong(_) => {},
+                NetworkMessage::Goodbye => return Ok(()),
+                network_message => match tag(network_message) {
+                    Ok(tagged) => send(tagged, &output).await?,
+                    Err(Some(refusal)) => replies.push(*refusal).await?,
+                    Err(None) => {}
+                }
+            }
+        }
+    }
 
-            let network_message = NetworkMessage::from_bytes(&buf)?;
-            output.send(network_message).await.map_err(ChannelError::from)?;
+    /// Write queued messages, control traffic first, until the outbox is closed and drained.
+    pub async fn send(mut client: impl AsyncWrite + Unpin, mut sealer: Sealer, codec: Codec, input: Inbox<NetworkMessage>) -> Res<()> {
+        while let Some(message) = input.next().await {
+            let bytes = sealer.seal(codec.encode(&message)?)?;
+            codec.write_frame(&mut client, &bytes).await?;
         }
+
+        client.shutdown().await?;
+        Ok(())
     }
 
-    pub async fn send(mut client: OwnedWriteHalf, input: Receiver<NetworkMessage>) -> Res<()> {
-        while let Ok(message) = input.recv_blocking() {
+    /// Addresses of every controller currently connected, in order of connection.
+    pub fn get_active_connections(&self) -> Vec<SocketAddr> {
+        let connections = self.connections.lock().unwrap();
+        let mut peers: Vec<(&PeerId, &Peer)> = connections.iter().collect();
+        peers.sort_by_key(|(peer_id, _)| **peer_id);
+        peers.into_iter().map(|(_, peer)| peer.address).collect()
+    }
 
-            if let NetworkMessage::TerminateThread = message {
-                client.shutdown().await?;
-                break;
-            }
+    /// The PIN a new controller must enter to pair with this display.
+    pub fn get_pin(&self) -> String {
+        self.pin.lock().unwrap().clone()
+    }
+
+    /// Issue a PIN that pairs one guest controller, whose access ends after the given number of minutes, at most a day.
+    /// Replaces any guest PIN not yet used. Returns the PIN and its expiry in seconds since the epoch.
+    pub fn issue_guest_pin(&self, minutes: u64) -> Res<(String, u64)> {
+        let lifetime = minutes.checked_mul(60).filter(|&lifetime| lifetime <= MAX_GUEST_LIFETIME.as_secs()).ok_or(ApplicationError::GuestLifetimeTooLong(minutes))?;
+
+        let guest_pin = GuestPin { pin: secure::random_pin(), expires_at: roles::now().saturating_add(lifetime) };
+        *self.guest_pin.lock().unwrap() = Some(guest_pin.clone());
+        Ok((guest_pin.pin, guest_pin.expires_at))
+    }
 
-            let bytes = message.to_bytes()?;
-            let size: u32 = bytes.len() as u32;
-            let endians = size.to_be_bytes().to_vec();
+    /// Every controller paired with this display, marking those connected now.
+    pub fn controllers(&self) -> impl Future<Output = Res<Vec<Controller>>> + Send + 'static {
+        let database = self.database.clone();
+        let connections = self.connections.clone();
 
-            client.write_all(&endians).await?;
-            client.write_all(&bytes).await?;
+        async move {
+            let mut controllers = interface::select_controllers(database).await?;
+            let connections = connections.lock().unwrap();
+            for controller in controllers.iter_mut() {
+                controller.connected = connections.values().any(|peer| peer.controller_id == controller.controller_id);
+            }
+            Ok(controllers)
         }
+    }
 
+    /// Change the role of a paired controller, taking effect on its connections straight away.
+    /// The last owner cannot give up the role, or nobody could manage the display any more.
+    pub fn set_role(&self, controller_id: String, role: Role) -> impl Future<Output = Res<()>> + Send + 'static {
+        let database = self.database.clone();
+        let connections = self.connections.clone();
+
+        async move {
+            let controllers = interface::select_controllers(database.clone()).await?;
+            let controller = controllers
+                .iter()
+                .find(|controller| controller.controller_id == controller_id)
+                .ok_or(ApplicationError::NoSuchController)?;
+
+            let owners = controllers.iter().filter(|controller| controller.role == Role::Owner).count();
+            if controller.role == Role::Owner && role != Role::Owner && owners == 1 {
+                return Err(ApplicationError::LastOwner.into());
+            }
 
-        Ok(())
+            // Promoting a guest makes its access permanent
+            let access = Access {
+                role,
+                expires_at: match role {
+                    Role::Guest => controller.expires_at,
+                    _ => None
+                }
+            };
+            interface::insert_controller_role(database, controller_id.clone(), access).await?;
+
+            for peer in connections.lock().unwrap().values_mut().filter(|peer| peer.controller_id == controller_id) {
+                peer.access = access;
+            }
+            Ok(())
+        }
+    }
+
+    /// Update the status advertised to controllers browsing for displays.
+    pub fn set_status(&self, status: DisplayStatus) {
+        let mut current = self.status.lock().unwrap();
+        *current = status;
     }
 
-    pub fn get_active_connection(&self) -> Option<IpAddr> {
-        let connection = self.active_connection.lock().unwrap();
-        *connection
+    /// The bearer token for the HTTP API, once it is being served.
+    pub fn get_api_token(&self) -> Option<String> {
+        self.api_token.lock().unwrap().clone()
     }
 
-    pub fn get_sender(&self) -> Sender<NetworkMessage> {
+    pub fn get_sender(&self) -> Outbox<(Recipient, NetworkMessage)> {
         self.sender.clone()
     }
 
-    pub async fn send_network_message(sender: Sender<NetworkMessage>, message: NetworkMessage) -> Res<()> {
-        send(message, &sender).await?;
+    pub async fn send_network_message(sender: Outbox<(Recipient, NetworkMessage)>, recipient: Recipient, message: NetworkMessage) -> Res<()> {
+        sender.push((recipient, message)).await?;
         Ok(())
     }
+
+    /// Send a series of messages, such as the chunks of a photo, reading the next only once there is room to queue it.
+    pub async fn send_network_messages(sender: Outbox<(Recipient, NetworkMessage)>, recipient: Recipient, messages: impl Stream<Item = NetworkMessage>) -> Res<()> {
+        let mut messages = std::pin::pin!(messages);
+
+        while let Some(message) = messages.next().await {
+            sender.push((recipient, message)).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::authentication::oauth2::api::TokenSet;
+    use crate::communication::client::{Client, Connector, REQUEST_TIMEOUT};
+    use crate::communication::transport;
+    use crate::database::scratch::ScratchDatabase;
+
+    const WAIT: Duration = Duration::from_secs(10);
+
+    /// A display served entirely in memory, with the database behind it.
+    pub struct Display {
+        pub server: Arc<Server>,
+        pub receiver: Receiver<(PeerId, NetworkMessage)>,
+        _database: ScratchDatabase
+    }
+
+    pub async fn database() -> ScratchDatabase {
+        ScratchDatabase::migrated().await
+    }
+
+    pub async fn display() -> Display {
+        let database = database().await;
+        let (server, receiver) = Server::spawn(database.derive(), ServerConfig { offline: true, ..ServerConfig::default() });
+
+        // The PIN is chosen once the server thread has started
+        timeout(WAIT, async { while server.get_pin().is_empty() { tokio::task::yield_now().await; } }).await.unwrap();
+
+        Display { server: Arc::new(server), receiver, _database: database }
+    }
+
+    /// Opens a fresh in-memory pair for every connection attempt, handing the display's end to the server.
+    pub fn connector(server: Arc<Server>) -> Connector {
+        Arc::new(move || {
+            let server = server.clone();
+            Box::pin(async move {
+                let (controller_end, display_end) = transport::memory();
+                server.attach(display_end, SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await?;
+                Ok(Box::new(controller_end) as Box<dyn Transport>)
+            })
+        })
+    }
+
+    /// A controller with a database of its own, paired with `pin` when it has not paired before.
+    pub async fn controller(display: &Display, database: &ScratchDatabase, pin: Option<String>) -> Res<(Arc<Client>, Receiver<NetworkMessage>)> {
+        let (client, receiver) = Client::spawn_over(connector(display.server.clone()), database.derive(), pin, Heartbeat::default()).await?;
+        Ok((Arc::new(client), receiver))
+    }
+
+    /// The next message matching `wanted`, skipping everything else.
+    pub async fn next<T>(receiver: &Receiver<T>, wanted: impl Fn(&T) -> bool) -> T {
+        timeout(WAIT, async {
+            loop {
+                let message = receiver.recv().await.unwrap();
+                if wanted(&message) { return message; }
+            }
+        }).await.expect("the expected message never arrived")
+    }
+
+    #[tokio::test]
+    async fn pairing_then_resuming_without_a_pin() {
+        let display = display().await;
+        let database = database().await;
+
+        let (client, receiver) = controller(&display, &database, Some(display.server.get_pin())).await.unwrap();
+        next(&display.receiver, |(_, message)| matches!(message, NetworkMessage::ConnectionState(ConnectionState::Connected))).await;
+        drop((client, receiver));
+
+        // The pairing key saved by the first connection is enough from now on
+        controller(&display, &database, None).await.unwrap();
+
+        let controllers = display.server.controllers().await.unwrap();
+        assert_eq!(controllers.len(), 1);
+        assert_eq!(controllers[0].role, Role::Owner);
+    }
+
+    #[tokio::test]
+    async fn a_wrong_pin_is_rejected() {
+        let display = display().await;
+        let database = database().await;
+
+        let wrong_pin = format!("{:06}", (display.server.get_pin().parse::<u32>().unwrap() + 1) % 1_000_000);
+        let error = controller(&display, &database, Some(wrong_pin)).await.err().unwrap();
+
+        assert!(matches!(error, Error::SecureChannelError(error) if matches!(error.as_ref(), SecureChannelError::PairingRejected)));
+        assert!(display.server.controllers().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn requests_are_answered_by_the_application() {
+        let display = display().await;
+        let database = database().await;
+        // The client stops routing replies once nothing reads what it passes on
+        let (client, _receiver) = controller(&display, &database, Some(display.server.get_pin())).await.unwrap();
+
+        let request = spawn(client.clone().request(NetworkMessage::RequestAllAlbums, REQUEST_TIMEOUT));
+
+        let (peer, message) = next(&display.receiver, |(_, message)| matches!(message, NetworkMessage::RequestAllAlbums(_))).await;
+        let NetworkMessage::RequestAllAlbums(id) = message else { unreachable!() };
+        Server::send_network_message(display.server.get_sender(), Recipient::Peer(peer), NetworkMessage::ReturnAllAlbums(id, Vec::new())).await.unwrap();
+
+        assert!(matches!(request.await.unwrap().unwrap(), NetworkMessage::ReturnAllAlbums(reply, albums) if reply == id && albums.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn disconnects_are_reported_to_both_ends() {
+        let display = display().await;
+        let database = database().await;
+
+        let (client, receiver) = controller(&display, &database, Some(display.server.get_pin())).await.unwrap();
+        let (peer, _) = next(&display.receiver, |(_, message)| matches!(message, NetworkMessage::ConnectionState(ConnectionState::Connected))).await;
+
+        // A controller that goes away is noticed by the display
+        drop((client, receiver));
+        next(&display.receiver, |(lost, message)| *lost == peer && matches!(message, NetworkMessage::ConnectionState(ConnectionState::Lost))).await;
+        assert!(display.server.get_active_connections().is_empty());
+
+        // A display that shuts down says goodbye to its controllers
+        let (_client, receiver) = controller(&display, &database, None).await.unwrap();
+        next(&display.receiver, |(_, message)| matches!(message, NetworkMessage::ConnectionState(ConnectionState::Connected))).await;
+
+        display.server.shutdown().await.unwrap();
+        next(&receiver, |message| matches!(message, NetworkMessage::ConnectionState(ConnectionState::Closed))).await;
+    }
+
+    /// Whether a request was refused by the display itself for lack of a role.
+    fn refused(result: Res<NetworkMessage>) -> bool {
+        matches!(result, Err(Error::ApplicationError(error)) if matches!(error.as_ref(), ApplicationError::RequestFailed(_, _)))
+    }
+
+    #[tokio::test]
+    async fn only_owners_may_manage_the_display() {
+        let display = display().await;
+        let (owner_database, viewer_database) = (database().await, database().await);
+
+        let (owner, _owner_receiver) = controller(&display, &owner_database, Some(display.server.get_pin())).await.unwrap();
+        let (viewer, viewer_receiver) = controller(&display, &viewer_database, Some(display.server.get_pin())).await.unwrap();
+
+        let mut roles: Vec<Role> = display.server.controllers().await.unwrap().into_iter().map(|controller| controller.role).collect();
+        roles.sort_by_key(|role| role.name());
+        assert_eq!(roles, vec![Role::Owner, Role::Viewer]);
+
+        // Refused requests never reach the application, and are failed straight away
+        assert!(refused(viewer.clone().request(NetworkMessage::RequestControllers, REQUEST_TIMEOUT).await));
+        assert!(refused(viewer.clone().request(|id| NetworkMessage::Sharelink(id, String::from("https://1drv.ms/a/s!example")), REQUEST_TIMEOUT).await));
+        let tokenset = TokenSet { access_token: String::from("access"), refresh_token: String::from("refresh"), absolute_expiration: 0 };
+        Client::send_with(viewer.yield_sender(), NetworkMessage::TokenSet(tokenset)).await.unwrap();
+        next(&viewer_receiver, |message| matches!(message, NetworkMessage::PermissionDenied(Role::Owner))).await;
+
+        // Viewers may still look around, and owners may do everything
+        let viewing = spawn(viewer.clone().request(NetworkMessage::RequestAllAlbums, REQUEST_TIMEOUT));
+        let managing = spawn(owner.clone().request(NetworkMessage::RequestControllers, REQUEST_TIMEOUT));
+
+        for _ in 0..2 {
+            let (peer, message) = next(&display.receiver, |(_, message)| matches!(message, NetworkMessage::RequestAllAlbums(_) | NetworkMessage::RequestControllers(_))).await;
+            let reply = match message {
+                NetworkMessage::RequestAllAlbums(id) => NetworkMessage::ReturnAllAlbums(id, Vec::new()),
+                NetworkMessage::RequestControllers(id) => NetworkMessage::ReturnControllers(id, Vec::new()),
+                _ => unreachable!()
+            };
+            Server::send_network_message(display.server.get_sender(), Recipient::Peer(peer), reply).await.unwrap();
+        }
+
+        assert!(matches!(viewing.await.unwrap().unwrap(), NetworkMessage::ReturnAllAlbums(_, _)));
+        assert!(matches!(managing.await.unwrap().unwrap(), NetworkMessage::ReturnControllers(_, _)));
+    }
+
+    #[tokio::test]
+    async fn the_last_owner_cannot_be_demoted() {
+        let display = display().await;
+        let (first, second) = (database().await, database().await);
+
+        let _first = controller(&display, &first, Some(display.server.get_pin())).await.unwrap();
+        let _second = controller(&display, &second, Some(display.server.get_pin())).await.unwrap();
+
+        let controllers = display.server.controllers().await.unwrap();
+        let id_of = |role: Role| controllers.iter().find(|controller| controller.role == role).unwrap().controller_id.clone();
+        let (owner, viewer) = (id_of(Role::Owner), id_of(Role::Viewer));
+
+        let error = display.server.set_role(owner.clone(), Role::Viewer).await.err().unwrap();
+        assert!(matches!(error, Error::ApplicationError(error) if matches!(error.as_ref(), ApplicationError::LastOwner)));
+
+        // Once there is another owner, either may step down
+        display.server.set_role(viewer.clone(), Role::Owner).await.unwrap();
+        display.server.set_role(owner.clone(), Role::Viewer).await.unwrap();
+
+        let controllers = display.server.controllers().await.unwrap();
+        let role_of = |id: &str| controllers.iter().find(|controller| controller.controller_id == id).unwrap().role;
+        assert_eq!((role_of(&owner), role_of(&viewer)), (Role::Viewer, Role::Owner));
+    }
+
+    #[tokio::test]
+    async fn guest_pins_pair_once_and_expire() {
+        let display = display().await;
+        let (owner, guest, latecomer) = (database().await, database().await, database().await);
+        let _owner = controller(&display, &owner, Some(display.server.get_pin())).await.unwrap();
+        next(&display.receiver, |(_, message)| matches!(message, NetworkMessage::ConnectionState(ConnectionState::Connected))).await;
+
+        let (pin, expires_at) = display.server.issue_guest_pin(1).unwrap();
+
+        // A failed attempt does not use up the guest PIN
+        let wrong_pin = format!("{:06}", (pin.parse::<u32>().unwrap() + 1) % 1_000_000);
+        assert!(controller(&display, &guest, Some(wrong_pin)).await.is_err());
+
+        let (guest_client, guest_receiver) = controller(&display, &guest, Some(pin.clone())).await.unwrap();
+        let (guest_peer, _) = next(&display.receiver, |(_, message)| matches!(message, NetworkMessage::ConnectionState(ConnectionState::Connected))).await;
+        let guest_controller = display.server.controllers().await.unwrap().into_iter().find(|controller| controller.role == Role::Guest).unwrap();
+        assert_eq!(guest_controller.expires_at, Some(expires_at));
+
+        // The PIN pairs a single guest
+        assert!(controller(&display, &latecomer, Some(pin)).await.is_err());
+
+        // A guest PIN past its expiry pairs nobody
+        let (pin, _) = display.server.issue_guest_pin(1).unwrap();
+        display.server.guest_pin.lock().unwrap().as_mut().unwrap().expires_at = roles::now() - 1;
+        assert!(controller(&display, &latecomer, Some(pin)).await.is_err());
+
+        // A connected guest whose access runs out is refused and disconnected
+        let expired = Access { role: Role::Guest, expires_at: Some(roles::now() - 1) };
+        interface::insert_controller_role(display.server.database.clone(), guest_controller.controller_id.clone(), expired).await.unwrap();
+        for peer in display.server.connections.lock().unwrap().values_mut().filter(|peer| peer.controller_id == guest_controller.controller_id) {
+            peer.access = expired;
+        }
+
+        assert!(refused(guest_client.clone().request(NetworkMessage::RequestAllAlbums, REQUEST_TIMEOUT).await));
+        next(&display.receiver, |(lost, message)| *lost == guest_peer && matches!(message, NetworkMessage::ConnectionState(ConnectionState::Lost))).await;
+        next(&guest_receiver, |message| matches!(message, NetworkMessage::ConnectionState(ConnectionState::Lost | ConnectionState::Closed))).await;
+
+        // Nor can it come back
+        drop((guest_client, guest_receiver));
+        assert!(controller(&display, &guest, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn a_stalled_controller_does_not_hold_up_bulk_traffic_to_others() {
+        let display = display().await;
+        let (stalled_database, healthy_database) = (database().await, database().await);
+
+        // Never read from, so once its buffers fill the display cannot queue anything more for it
+        let (_stalled, _stalled_receiver) = controller(&display, &stalled_database, Some(display.server.get_pin())).await.unwrap();
+        let (stalled_peer, _) = next(&display.receiver, |(_, message)| matches!(message, NetworkMessage::ConnectionState(ConnectionState::Connected))).await;
+        let (_healthy, healthy_receiver) = controller(&display, &healthy_database, Some(display.server.get_pin())).await.unwrap();
+        let (healthy_peer, _) = next(&display.receiver, |(_, message)| matches!(message, NetworkMessage::ConnectionState(ConnectionState::Connected))).await;
+
+        let sender = display.server.get_sender();
+        let chunk = |id| NetworkMessage::PhotoChunk(id, 0, vec![0; 1024]);
+        let flood = spawn(Server::send_network_messages(sender.clone(), Recipient::Peer(stalled_peer), futures_util::stream::iter((0..4096).map(chunk))));
+
+        Server::send_network_message(sender, Recipient::Peer(healthy_peer), chunk(u64::MAX)).await.unwrap();
+        next(&healthy_receiver, |message| matches!(message, NetworkMessage::PhotoChunk(u64::MAX, _, _))).await;
+
+        next(&display.receiver, |(lost, message)| *lost == stalled_peer && matches!(message, NetworkMessage::ConnectionState(ConnectionState::Lost))).await;
+        flood.abort();
+    }
+
+    #[tokio::test]
+    async fn guest_pins_last_at_most_a_day() {
+        let display = display().await;
+
+        let (_, expires_at) = display.server.issue_guest_pin(MAX_GUEST_LIFETIME.as_secs() / 60).unwrap();
+        assert!(expires_at <= roles::now() + MAX_GUEST_LIFETIME.as_secs());
+
+        // Too long a lifetime, including one whose seconds overflow, is refused and leaves no PIN behind
+        for minutes in [MAX_GUEST_LIFETIME.as_secs() / 60 + 1, u64::MAX] {
+            *display.server.guest_pin.lock().unwrap() = None;
+            let result = display.server.issue_guest_pin(minutes);
+            assert!(matches!(result, Err(Error::ApplicationError(error)) if matches!(error.as_ref(), ApplicationError::GuestLifetimeTooLong(too_long) if *too_long == minutes)));
+            assert!(display.server.guest_pin.lock().unwrap().is_none());
+        }
+    }
 }
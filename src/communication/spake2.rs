@@ -0,0 +1,109 @@
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_TABLE;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::Identity;
+use ring::rand::{SecureRandom, SystemRandom};
+use sha2::{Digest, Sha512};
+
+use crate::communication::secure::SecureChannelError;
+use crate::error::Res;
+
+/// Bytes of a compressed Ristretto point on the wire.
+pub const ELEMENT_LENGTH: usize = 32;
+
+/// Which end of the exchange this is. Each masks its message with its own constant, so a message cannot be reflected back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Controller,
+    Display
+}
+
+/// One side of a SPAKE2 exchange over the Ristretto group, authenticating with a secret as weak as a six digit PIN.
+/// Watching or taking part in an exchange allows a single guess at the secret, never an offline search.
+/// All arithmetic on secrets is done by curve25519-dalek, in constant time.
+pub struct Spake2 {
+    exponent: Scalar,
+    password: Scalar,
+    side: Side,
+    message: Vec<u8>
+}
+
+impl Spake2 {
+    /// Pick an ephemeral scalar and compute the message for the peer: x·G plus this side's constant times the secret.
+    pub fn start(side: Side, secret: &[u8]) -> Res<Spake2> {
+        let mut exponent = [0u8; 64];
+        SystemRandom::new().fill(&mut exponent).map_err(|_| SecureChannelError::KeyAgreementFailed)?;
+        let exponent = Scalar::from_bytes_mod_order_wide(&exponent);
+
+        let password = Scalar::from_hash(Sha512::new().chain_update(b"reflection spake2 password").chain_update(secret));
+        let message = (&exponent * RISTRETTO_BASEPOINT_TABLE + constant(side) * password).compress().to_bytes().to_vec();
+
+        Ok(Spake2 { exponent, password, side, message })
+    }
+
+    pub fn message(&self) -> &[u8] {
+        &self.message
+    }
+
+    /// The shared secret, equal on both sides only if they used the same secret.
+    /// Can be called with several peer messages, as a controller does with each PIN a display might accept.
+    pub fn finish(&self, peer_message: &[u8]) -> Res<Vec<u8>> {
+        let peer = CompressedRistretto::from_slice(peer_message)
+            .ok()
+            .and_then(|peer| peer.decompress())
+            .ok_or(SecureChannelError::KeyAgreementFailed)?;
+
+        let peer_side = match self.side {
+            Side::Controller => Side::Display,
+            Side::Display => Side::Controller
+        };
+
+        let shared = (peer - constant(peer_side) * self.password) * self.exponent;
+        if shared == RistrettoPoint::identity() {
+            return Err(SecureChannelError::KeyAgreementFailed.into());
+        }
+
+        Ok(shared.compress().to_bytes().to_vec())
+    }
+}
+
+/// A point nobody knows the discrete logarithm of, hashed onto the group from a label.
+fn constant(side: Side) -> RistrettoPoint {
+    RistrettoPoint::hash_from_bytes::<Sha512>(match side {
+        Side::Controller => b"reflection spake2 M",
+        Side::Display => b"reflection spake2 N"
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching_secrets_agree() {
+        let controller = Spake2::start(Side::Controller, b"123456").unwrap();
+        let display = Spake2::start(Side::Display, b"123456").unwrap();
+
+        assert_eq!(controller.finish(display.message()).unwrap(), display.finish(controller.message()).unwrap());
+    }
+
+    #[test]
+    fn different_secrets_disagree() {
+        let controller = Spake2::start(Side::Controller, b"123456").unwrap();
+        let display = Spake2::start(Side::Display, b"654321").unwrap();
+
+        assert_ne!(controller.finish(display.message()).unwrap(), display.finish(controller.message()).unwrap());
+    }
+
+    #[test]
+    fn malformed_elements_are_refused() {
+        let display = Spake2::start(Side::Display, b"123456").unwrap();
+
+        assert!(display.finish(&[0xff; ELEMENT_LENGTH]).is_err());
+        assert!(display.finish(&[0u8; 12]).is_err());
+
+        // The peer's constant times the secret, which would leave nothing of the exchange to share
+        let password = Scalar::from_hash(Sha512::new().chain_update(b"reflection spake2 password").chain_update(b"123456"));
+        assert!(display.finish((constant(Side::Controller) * password).compress().as_bytes()).is_err());
+    }
+}
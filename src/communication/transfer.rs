@@ -0,0 +1,177 @@
+use std::path::PathBuf;
+
+use futures_util::{Stream, stream};
+use ring::digest::{Context, SHA256};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+use crate::communication::{NetworkMessage, RequestId};
+use crate::error::Res;
+use crate::frontend::application::ApplicationError;
+
+/// Largest slice of a file carried by a single frame.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How much of a transfer has arrived, reported after every chunk.
+#[derive(Clone, Copy, Debug)]
+pub struct TransferProgress {
+    pub id: RequestId,
+    pub received: u64,
+    pub total: u64
+}
+
+enum Step {
+    Open(PathBuf),
+    // (file, digest of everything sent so far, offset of the next chunk)
+    Chunk(File, Box<Context>, u64)
+}
+
+/// Stream a file in answer to request `id`: its size, the chunks, then a SHA-256 digest of the whole.
+/// Reading stops at the first error, which is yielded last.
+pub fn stream_file(id: RequestId, path: PathBuf) -> impl Stream<Item = Res<NetworkMessage>> {
+    stream::unfold(Some(Step::Open(path)), move |step| async move {
+        match step? {
+            Step::Open(path) => {
+                let opened = async {
+                    let file = File::open(&path).await?;
+                    let total = file.metadata().await?.len();
+                    Res::Ok((file, total))
+                }.await;
+
+                match opened {
+                    Ok((file, total)) => Some((Ok(NetworkMessage::PhotoStart(id, total)), Some(Step::Chunk(file, Box::new(Context::new(&SHA256)), 0)))),
+                    Err(e) => Some((Err(e), None))
+                }
+            },
+
+            Step::Chunk(mut file, mut context, offset) => {
+                let mut chunk = vec![0u8; CHUNK_SIZE];
+
+                match file.read(&mut chunk).await {
+                    Ok(0) => Some((Ok(NetworkMessage::PhotoEnd(id, context.finish().as_ref().to_vec())), None)),
+                    Ok(length) => {
+                        chunk.truncate(length);
+                        context.update(&chunk);
+                        Some((Ok(NetworkMessage::PhotoChunk(id, offset, chunk)), Some(Step::Chunk(file, context, offset + length as u64))))
+                    },
+                    Err(e) => Some((Err(e.into()), None))
+                }
+            }
+        }
+    })
+}
+
+/// Puts a streamed file back together, checking the chunks arrive in order and match the final digest.
+pub struct Reassembly {
+    id: RequestId,
+    total: u64,
+    bytes: Vec<u8>,
+    context: Context
+}
+
+impl Reassembly {
+
+    pub fn new(id: RequestId) -> Self {
+        Self { id, total: 0, bytes: Vec::new(), context: Context::new(&SHA256) }
+    }
+
+    /// Take the next reply to the transfer, returning the file once the final reply checks out.
+    pub fn accept(&mut self, message: NetworkMessage) -> Res<Option<Vec<u8>>> {
+        match message {
+            NetworkMessage::PhotoStart(_, total) => {
+                self.total = total;
+                Ok(None)
+            },
+
+            NetworkMessage::PhotoChunk(_, offset, chunk) => {
+                if offset != self.bytes.len() as u64 || offset + chunk.len() as u64 > self.total {
+                    return Err(ApplicationError::TransferCorrupted(self.id).into());
+                }
+
+                self.context.update(&chunk);
+                self.bytes.extend_from_slice(&chunk);
+                Ok(None)
+            },
+
+            NetworkMessage::PhotoEnd(_, digest) => {
+                if self.bytes.len() as u64 != self.total || self.context.clone().finish().as_ref() != digest.as_slice() {
+                    return Err(ApplicationError::TransferCorrupted(self.id).into());
+                }
+
+                Ok(Some(std::mem::take(&mut self.bytes)))
+            },
+
+            NetworkMessage::RequestFailed(_, reason) => Err(ApplicationError::RequestFailed(self.id, reason).into()),
+
+            _ => Err(ApplicationError::UnexpectedReply.into())
+        }
+    }
+
+    pub fn progress(&self) -> TransferProgress {
+        TransferProgress { id: self.id, received: self.bytes.len() as u64, total: self.total }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::digest::digest;
+
+    use super::*;
+    use crate::error::Error;
+
+    const ID: RequestId = 7;
+
+    fn corrupted(result: Res<Option<Vec<u8>>>) -> bool {
+        matches!(result, Err(Error::ApplicationError(error)) if matches!(error.as_ref(), ApplicationError::TransferCorrupted(ID)))
+    }
+
+    fn started(total: u64) -> Reassembly {
+        let mut reassembly = Reassembly::new(ID);
+        assert!(reassembly.accept(NetworkMessage::PhotoStart(ID, total)).unwrap().is_none());
+        reassembly
+    }
+
+    #[test]
+    fn chunks_in_order_rebuild_the_file() {
+        let file = b"a photo in two chunks".to_vec();
+        let mut reassembly = started(file.len() as u64);
+
+        assert!(reassembly.accept(NetworkMessage::PhotoChunk(ID, 0, file[..8].to_vec())).unwrap().is_none());
+        assert!(reassembly.accept(NetworkMessage::PhotoChunk(ID, 8, file[8..].to_vec())).unwrap().is_none());
+        assert_eq!(reassembly.progress().received, file.len() as u64);
+
+        let digest = digest(&SHA256, &file).as_ref().to_vec();
+        assert_eq!(reassembly.accept(NetworkMessage::PhotoEnd(ID, digest)).unwrap(), Some(file));
+    }
+
+    #[test]
+    fn out_of_order_chunks_are_refused() {
+        let mut reassembly = started(16);
+        assert!(corrupted(reassembly.accept(NetworkMessage::PhotoChunk(ID, 8, vec![0; 8]))));
+    }
+
+    #[test]
+    fn duplicate_chunks_are_refused() {
+        let mut reassembly = started(16);
+        assert!(reassembly.accept(NetworkMessage::PhotoChunk(ID, 0, vec![0; 8])).unwrap().is_none());
+        assert!(corrupted(reassembly.accept(NetworkMessage::PhotoChunk(ID, 0, vec![0; 8]))));
+    }
+
+    #[test]
+    fn chunks_past_the_announced_size_are_refused() {
+        let mut reassembly = started(16);
+        assert!(reassembly.accept(NetworkMessage::PhotoChunk(ID, 0, vec![0; 12])).unwrap().is_none());
+        assert!(corrupted(reassembly.accept(NetworkMessage::PhotoChunk(ID, 12, vec![0; 8]))));
+    }
+
+    #[test]
+    fn a_short_file_or_wrong_digest_is_refused() {
+        let mut short = started(16);
+        short.accept(NetworkMessage::PhotoChunk(ID, 0, vec![0; 8])).unwrap();
+        assert!(corrupted(short.accept(NetworkMessage::PhotoEnd(ID, digest(&SHA256, &[0; 8]).as_ref().to_vec()))));
+
+        let mut tampered = started(8);
+        tampered.accept(NetworkMessage::PhotoChunk(ID, 0, vec![0; 8])).unwrap();
+        assert!(corrupted(tampered.accept(NetworkMessage::PhotoEnd(ID, digest(&SHA256, &[1; 8]).as_ref().to_vec()))));
+    }
+}
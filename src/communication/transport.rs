@@ -0,0 +1,17 @@
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, duplex};
+
+/// Buffered in each direction of an in-memory pair, enough for several full chunks of a photo transfer.
+#[cfg_attr(not(test), allow(dead_code))]
+const MEMORY_BUFFER: usize = 256 * 1024;
+
+/// A reliable, ordered byte stream the protocol can run over. TCP is the usual one.
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Transport for T {}
+
+/// Two connected in-memory transports, one for each end, so a display and a controller can talk within one process.
+/// Only the tests do so far.
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn memory() -> (DuplexStream, DuplexStream) {
+    duplex(MEMORY_BUFFER)
+}
@@ -1,18 +1,26 @@
-use crate::{database::sql::{self, SELECT_ALBUM_BY_ID}, error::Res, frontend::application::ApplicationError, onedrive::get_album_children::{Album, LocationData, Photo}};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+
+use crate::{communication::{discovery::KnownDisplay, roles::{Access, Controller, Role}, secure::Pairing}, database::sql::{self, SELECT_ALBUM_BY_ID}, error::Res, frontend::application::ApplicationError, onedrive::get_album_children::{Album, ItemTags, LocationData, Photo}};
 use rusqlite_async::database::{DataLink, DatabaseParam, DatabaseParams};
 
 #[derive(Clone, Debug)]
 pub enum DatabaseInterfaceError {
     IncorrectNumberOfRows,
     MalformedRow,
+    // (version, what went wrong) for a migration that was rolled back
+    MigrationFailed(usize, String),
+    // The database was migrated by a newer build, to this version
+    SchemaTooNew(usize),
 }
 
-/// Create the tables without checking for success. If this fails, later DB calls will indicate.
-pub fn create_tables(database: DataLink) -> Res<()> {
-    database.execute(sql::CREATE_TOKEN_TABLE, DatabaseParams::empty())?;
-    database.execute(sql::CREATE_ALBUM_TABLE, DatabaseParams::empty())?;
-    database.execute(sql::CREATE_PHOTO_TABLE, DatabaseParams::empty())?;
-    database.execute(sql::CREATE_ENTRY_TABLE, DatabaseParams::empty())?;
+/// Wait for every statement queued before this one to run. Statements run one at a time, in order, and each commits
+/// on its own, so everything sent before this is in the database file once it returns.
+/// Called before exiting so that nothing sent without waiting is lost.
+pub async fn flush(database: DataLink) -> Res<()> {
+    database.query_map(sql::BARRIER, DatabaseParams::empty()).await?;
     Ok(())
 }
 
@@ -38,7 +46,7 @@ pub async fn retrieve_token(database: DataLink) -> Res<(String, usize)> {
 /// Use the onedrive_id to select an album
 pub async fn select_album_by_id(database: DataLink, onedrive_id: String) -> Res<Option<Album>> {
     Ok(
-        database.query_map(sql::SELECT_ALBUM_BY_ID, DatabaseParams::single(DatabaseParam::String(onedrive_id)))
+        database.query_map(sql::SELECT_ALBUM_BY_ONEDRIVE_ID, DatabaseParams::single(DatabaseParam::String(onedrive_id)))
             .await?
             .into_iter()
             .filter_map(parse_row_into_album)
@@ -49,7 +57,7 @@ pub async fn select_album_by_id(database: DataLink, onedrive_id: String) -> Res<
 /// Use the onedrive_id to select a photo
 pub async fn select_photo_by_id(database: DataLink, onedrive_id: String) -> Res<Option<Photo>> {
     Ok(
-        database.query_map(sql::SELECT_PHOTO_BY_ID, DatabaseParams::single(DatabaseParam::String(onedrive_id)))
+        database.query_map(sql::SELECT_PHOTO_BY_ONEDRIVE_ID, DatabaseParams::single(DatabaseParam::String(onedrive_id)))
             .await?
             .into_iter()
             .filter_map(parse_row_into_photo)
@@ -57,59 +65,54 @@ pub async fn select_photo_by_id(database: DataLink, onedrive_id: String) -> Res<
     )
 }
 
-/// Insert a new album record, and returning the completed album object
-pub async fn insert_album(database: DataLink, mut album: Album) -> Res<Album> {
+/// Insert an album record, or bring the existing one with the same onedrive_id up to date, returning it as stored
+pub async fn upsert_album(database: DataLink, album: Album) -> Res<Album> {
+    database.execute_and_wait(sql::UPSERT_ALBUM, DatabaseParams::new(vec![
+        DatabaseParam::String(album.onedrive_id.clone()),
+        DatabaseParam::String(album.name),
+        DatabaseParam::String(album.share_link)
+    ])).await?;
 
-    // Check if the album exists in the database yet (based on unique onedrive_id)
-    match select_album_by_id(database.clone(), album.onedrive_id.clone()).await? {
-        Some(existing_album) => Ok(existing_album),
-        None => {
-            let (row_id, _) = database.insert(sql::INSERT_ALBUM, DatabaseParams::new(vec![
-                DatabaseParam::String(album.onedrive_id.clone()),
-                DatabaseParam::String(album.name.clone()),
-                DatabaseParam::String(album.share_link.clone())
-            ])).await?;
+    Ok(select_album_by_id(database, album.onedrive_id).await?.ok_or(DatabaseInterfaceError::IncorrectNumberOfRows)?)
+}
 
-            album.id = row_id;
-            Ok(album)
-        }
-    }
+/// Insert a photo record, or bring the existing one with the same onedrive_id up to date, returning it as stored.
+/// Also returns whether the content of an existing photo changed since it was last stored, going by its cTag.
+pub async fn upsert_photo(database: DataLink, photo: Photo, tags: ItemTags) -> Res<(Photo, bool)> {
+    // None for a new photo, Some(None) for one stored before tags were kept
+    let previous_ctag = database.query_map(sql::SELECT_PHOTO_CTAG_BY_ONEDRIVE_ID, DatabaseParams::single(DatabaseParam::String(photo.onedrive_id.clone())))
+        .await?
+        .into_iter()
+        .filter_map(|row| row.into_iter().next())
+        .map(|value| match value {
+            DatabaseParam::String(ctag) => Some(ctag),
+            _ => None
+        })
+        .next();
 
-}
+    let nullable_f64 = |value: Option<f64>| value.map(DatabaseParam::F64).unwrap_or(DatabaseParam::Null);
+    let (latitude, longitude, altitude) = match photo.location.as_ref() {
+        Some(location) => (Some(location.latitude), Some(location.longitude), location.altitude),
+        None => (None, None, None)
+    };
 
-/// Insert a new photo record
-pub async fn insert_photo(database: DataLink, mut photo: Photo) -> Res<Photo> {
-
-    // Check if the photo already exists
-    match select_photo_by_id(database.clone(), photo.onedrive_id.clone()).await? {
-        Some(updated_photo) => Ok(updated_photo),
-        None => {
-            let time_string = match photo.creation_date {
-                Some(date) => date.to_string(),
-                None => String::from("NONE")
-            };
-
-            let (latitude, longitude, altitude) = match photo.location.as_ref() {
-                Some(location) => (location.latitude, location.longitude, location.altitude.unwrap_or(0f64)),
-                None => (0f64, 0f64, 0f64)
-            };
-
-            let (row_id, _) = database.insert(sql::INSERT_PHOTO, DatabaseParams::new(vec![
-                DatabaseParam::String(photo.onedrive_id.clone()),
-                DatabaseParam::String(photo.name.clone()),
-                DatabaseParam::String(time_string),
-                DatabaseParam::Usize(photo.width),
-                DatabaseParam::Usize(photo.height),
-                DatabaseParam::Usize(photo.filesize),
-                DatabaseParam::F64(latitude),
-                DatabaseParam::F64(longitude),
-                DatabaseParam::F64(altitude)
-            ])).await?;
-
-            photo.id = row_id;
-            Ok(photo)
-        }
-    }
+    database.execute_and_wait(sql::UPSERT_PHOTO, DatabaseParams::new(vec![
+        DatabaseParam::String(photo.onedrive_id.clone()),
+        DatabaseParam::String(photo.name),
+        photo.creation_date.map(|date| DatabaseParam::Usize(date as usize)).unwrap_or(DatabaseParam::Null),
+        DatabaseParam::Usize(photo.width),
+        DatabaseParam::Usize(photo.height),
+        DatabaseParam::Usize(photo.filesize),
+        nullable_f64(latitude),
+        nullable_f64(longitude),
+        nullable_f64(altitude),
+        tags.etag.map(DatabaseParam::String).unwrap_or(DatabaseParam::Null),
+        tags.ctag.clone().map(DatabaseParam::String).unwrap_or(DatabaseParam::Null)
+    ])).await?;
+
+    let changed = matches!((previous_ctag, tags.ctag), (Some(Some(previous)), Some(current)) if previous != current);
+    let photo = select_photo_by_id(database, photo.onedrive_id).await?.ok_or(DatabaseInterfaceError::IncorrectNumberOfRows)?;
+    Ok((photo, changed))
 }
 
 /// Insert an entry tagging a photo as part of an album
@@ -122,62 +125,113 @@ pub async fn insert_entry(database: DataLink, album_id: usize, photo_id: usize)
     Ok(row_id)
 }
 
+/// Take a photo out of an album
+pub async fn delete_entry(database: DataLink, album_id: usize, photo_id: usize) -> Res<()> {
+    database.execute_and_wait(sql::DELETE_ENTRY, DatabaseParams::new(vec![
+        DatabaseParam::Usize(album_id),
+        DatabaseParam::Usize(photo_id)
+    ])).await?;
+    Ok(())
+}
+
+/// Delete a photo record. Its entries must already be gone, foreign keys are not enforced.
+pub async fn delete_photo(database: DataLink, photo_id: usize) -> Res<()> {
+    database.execute_and_wait(sql::DELETE_PHOTO_BY_ID, DatabaseParams::single(DatabaseParam::Usize(photo_id))).await?;
+    Ok(())
+}
+
+/// Delete an album record along with its entries, leaving the photos that were in it
+pub async fn delete_album(database: DataLink, album_id: usize) -> Res<()> {
+    database.execute_and_wait(sql::DELETE_ENTRIES_BY_ALBUM_ID, DatabaseParams::single(DatabaseParam::Usize(album_id))).await?;
+    database.execute_and_wait(sql::DELETE_ALBUM_BY_ID, DatabaseParams::single(DatabaseParam::Usize(album_id))).await?;
+    Ok(())
+}
+
+/// Number of albums a photo is in
+pub async fn count_entries_for_photo(database: DataLink, photo_id: usize) -> Res<usize> {
+    let rows = database.query_map(sql::SELECT_ENTRY_COUNT_BY_PHOTO_ID, DatabaseParams::single(DatabaseParam::Usize(photo_id))).await?;
+    let row = rows.first().ok_or(DatabaseInterfaceError::IncorrectNumberOfRows)?;
+    match row.first() {
+        Some(DatabaseParam::Usize(count)) => Ok(*count),
+        _ => Err(DatabaseInterfaceError::MalformedRow.into())
+    }
+}
+
+/// A row keyed by column name, from a query selecting a known list of columns.
+/// Each getter gives None when the column is missing or holds a value of another type.
+struct Row(HashMap<&'static str, DatabaseParam>);
+
+impl Row {
+    fn new(columns: &[&'static str], values: Vec<DatabaseParam>) -> Option<Row> {
+        match columns.len() == values.len() {
+            true => Some(Row(columns.iter().copied().zip(values).collect())),
+            false => None
+        }
+    }
+
+    fn usize(&self, column: &str) -> Option<usize> {
+        match self.0.get(column)? {
+            DatabaseParam::Usize(value) => Some(*value),
+            _ => None
+        }
+    }
+
+    fn string(&self, column: &str) -> Option<String> {
+        match self.0.get(column)? {
+            DatabaseParam::String(value) => Some(value.clone()),
+            _ => None
+        }
+    }
+
+    /// Some(None) for NULL
+    fn nullable_usize(&self, column: &str) -> Option<Option<usize>> {
+        match self.0.get(column)? {
+            DatabaseParam::Null => Some(None),
+            _ => self.usize(column).map(Some)
+        }
+    }
+
+    /// Some(None) for NULL. Integers are taken too, in case a value was stored without REAL affinity.
+    fn nullable_f64(&self, column: &str) -> Option<Option<f64>> {
+        match self.0.get(column)? {
+            DatabaseParam::Null => Some(None),
+            DatabaseParam::F64(value) => Some(Some(*value)),
+            DatabaseParam::Usize(value) => Some(Some(*value as f64)),
+            DatabaseParam::String(_) => None
+        }
+    }
+}
+
 pub fn parse_row_into_photo(row: Vec<DatabaseParam>) -> Option<Photo> {
-    let mut iterator = row.into_iter();
-    let id = iterator.next()?.usize();
-    let onedrive_id = iterator.next()?.string();
-    let name = iterator.next()?.string();
-    let time_string = iterator.next()?.string();
-    let width = iterator.next()?.usize();
-    let height = iterator.next()?.usize();
-    let filesize = iterator.next()?.usize();
-    let raw_latitude = iterator.next()?.f64();
-    let raw_longitude = iterator.next()?.f64();
-    let raw_altitude = iterator.next()?.f64();
-
-    let creation_date = match time_string.as_str() {
-        "NONE" => None,
-        encoded_time => encoded_time.parse::<u64>().ok()
-    };
+    let row = Row::new(sql::PHOTO_COLUMNS, row)?;
 
-    let location = if raw_latitude == 0f64 && raw_longitude == 0f64 {
-        None
-    } else {
-        Some(
-            LocationData {
-                latitude: raw_latitude,
-                longitude: raw_longitude,
-                altitude: match raw_altitude {
-                    0f64 => None,
-                    nonzero => Some(nonzero)
-                }
-            }
-        )
+    let location = match (row.nullable_f64("latitude")?, row.nullable_f64("longitude")?) {
+        (Some(latitude), Some(longitude)) => Some(LocationData { latitude, longitude, altitude: row.nullable_f64("altitude")? }),
+        _ => None
     };
 
     Some(
         Photo {
-            id,
-            onedrive_id,
-            name,
-            creation_date,
-            width,
-            height,
-            filesize,
+            id: row.usize("id")?,
+            onedrive_id: row.string("onedrive_id")?,
+            name: row.string("name")?,
+            creation_date: row.nullable_usize("creation_date")?.map(|date| date as u64),
+            width: row.usize("width")?,
+            height: row.usize("height")?,
+            filesize: row.usize("filesize")?,
             location
         }
     )
 }
 
 pub fn parse_row_into_album(row: Vec<DatabaseParam>) -> Option<Album> {
-    let mut iterator = row.into_iter();
-    let id = iterator.next()?.usize();
-    let onedrive_id = iterator.next()?.string();
-    let name = iterator.next()?.string();
-    let share_link = iterator.next()?.string();
+    let row = Row::new(sql::ALBUM_COLUMNS, row)?;
 
     Some(Album {
-        id, onedrive_id, name, share_link
+        id: row.usize("id")?,
+        onedrive_id: row.string("onedrive_id")?,
+        name: row.string("name")?,
+        share_link: row.string("share_link")?
     })
 }
 
@@ -199,12 +253,12 @@ pub async fn select_photos_in_album(database: DataLink, album_id: usize) -> Res<
         .collect()))
 }
 
-/// Select all photos
-pub async fn select_all_photos(database: DataLink) -> Res<Vec<Photo>> {
-    Ok(database.query_map(sql::SELECT_ALL_PHOTOS, DatabaseParams::empty())
+/// Select the albums a photo is in
+pub async fn select_albums_with_photo(database: DataLink, photo_id: usize) -> Res<Vec<Album>> {
+    Ok(database.query_map(sql::SELECT_ALBUMS_BY_PHOTO_ID, DatabaseParams::single(DatabaseParam::Usize(photo_id)))
         .await?
         .into_iter()
-        .filter_map(parse_row_into_photo)
+        .filter_map(parse_row_into_album)
         .collect())
 }
 
@@ -216,3 +270,186 @@ pub async fn select_albums(database: DataLink) -> Res<Vec<Album>> {
         .filter_map(parse_row_into_album)
         .collect())
 }
+
+/// Read a single value from the key-value settings table
+pub async fn select_setting(database: DataLink, key: &str) -> Res<Option<String>> {
+    Ok(
+        database.query_map(sql::SELECT_SETTING, DatabaseParams::single(DatabaseParam::String(key.to_string())))
+            .await?
+            .into_iter()
+            .filter_map(|row| row.into_iter().next())
+            .map(|value| value.string())
+            .next()
+    )
+}
+
+/// Create or overwrite a value in the key-value settings table
+pub async fn insert_setting(database: DataLink, key: &str, value: String) -> Res<()> {
+    database.insert(sql::INSERT_SETTING, DatabaseParams::new(vec![
+        DatabaseParam::String(key.to_string()),
+        DatabaseParam::String(value)
+    ])).await?;
+    Ok(())
+}
+
+/// Remember a controller that completed pairing with this display
+pub async fn insert_controller(database: DataLink, controller_id: String, key: Vec<u8>) -> Res<()> {
+    let paired_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as usize;
+    database.insert(sql::INSERT_CONTROLLER, DatabaseParams::new(vec![
+        DatabaseParam::String(controller_id),
+        DatabaseParam::String(BASE64_URL_SAFE_NO_PAD.encode(key)),
+        DatabaseParam::Usize(paired_at)
+    ])).await?;
+    Ok(())
+}
+
+/// Look up the pairing key of a known controller
+pub async fn select_controller_key(database: DataLink, controller_id: String) -> Res<Option<Vec<u8>>> {
+    Ok(
+        database.query_map(sql::SELECT_CONTROLLER_KEY, DatabaseParams::single(DatabaseParam::String(controller_id)))
+            .await?
+            .into_iter()
+            .filter_map(|row| row.into_iter().next())
+            .filter_map(|value| BASE64_URL_SAFE_NO_PAD.decode(value.string()).ok())
+            .next()
+    )
+}
+
+/// Give a controller a role, replacing the one it had
+pub async fn insert_controller_role(database: DataLink, controller_id: String, access: Access) -> Res<()> {
+    database.insert(sql::INSERT_CONTROLLER_ROLE, DatabaseParams::new(vec![
+        DatabaseParam::String(controller_id),
+        DatabaseParam::String(access.role.name().to_string()),
+        match access.expires_at {
+            Some(expires_at) => DatabaseParam::Usize(expires_at as usize),
+            None => DatabaseParam::Null
+        }
+    ])).await?;
+    Ok(())
+}
+
+/// Every controller paired with this display, in order of pairing
+pub async fn select_controllers(database: DataLink) -> Res<Vec<Controller>> {
+    Ok(
+        database.query_map(sql::SELECT_CONTROLLERS, DatabaseParams::empty())
+            .await?
+            .into_iter()
+            .filter_map(parse_row_into_controller)
+            .collect()
+    )
+}
+
+/// Look up a paired controller and its role
+pub async fn select_controller(database: DataLink, controller_id: String) -> Res<Option<Controller>> {
+    Ok(
+        database.query_map(sql::SELECT_CONTROLLER_BY_CONTROLLER_ID, DatabaseParams::single(DatabaseParam::String(controller_id)))
+            .await?
+            .into_iter()
+            .filter_map(parse_row_into_controller)
+            .next()
+    )
+}
+
+pub fn parse_row_into_controller(row: Vec<DatabaseParam>) -> Option<Controller> {
+    let mut iterator = row.into_iter();
+    let controller_id = iterator.next()?.string();
+    let paired_at = iterator.next()?.usize() as u64;
+
+    // No role is recorded for controllers paired before roles existed
+    let role = match iterator.next()? {
+        DatabaseParam::String(role) => Role::parse(&role)?,
+        _ => Role::Owner
+    };
+    let expires_at = match iterator.next()? {
+        DatabaseParam::Usize(expires_at) => Some(expires_at as u64),
+        _ => None
+    };
+
+    Some(Controller {
+        controller_id, role, paired_at, expires_at, connected: false
+    })
+}
+
+/// Remember the credentials issued by a display, replacing any previous pairing with it
+pub async fn insert_pairing(database: DataLink, pairing: Pairing) -> Res<()> {
+    database.insert(sql::INSERT_PAIRING, DatabaseParams::new(vec![
+        DatabaseParam::String(pairing.display_id),
+        DatabaseParam::String(pairing.controller_id),
+        DatabaseParam::String(BASE64_URL_SAFE_NO_PAD.encode(pairing.key))
+    ])).await?;
+    Ok(())
+}
+
+/// Find the credentials this controller holds for a display
+pub async fn select_pairing(database: DataLink, display_id: String) -> Res<Option<Pairing>> {
+    Ok(
+        database.query_map(sql::SELECT_PAIRING_BY_DISPLAY_ID, DatabaseParams::single(DatabaseParam::String(display_id)))
+            .await?
+            .into_iter()
+            .filter_map(parse_row_into_pairing)
+            .next()
+    )
+}
+
+pub fn parse_row_into_pairing(row: Vec<DatabaseParam>) -> Option<Pairing> {
+    let mut iterator = row.into_iter();
+    let display_id = iterator.next()?.string();
+    let controller_id = iterator.next()?.string();
+    let key = BASE64_URL_SAFE_NO_PAD.decode(iterator.next()?.string()).ok()?;
+
+    Some(Pairing {
+        display_id, controller_id, key
+    })
+}
+
+/// Remember where a display was reached, replacing what was known about it before
+pub async fn insert_known_display(database: DataLink, display_id: String, name: String, address: String) -> Res<()> {
+    let last_seen = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as usize;
+    database.insert(sql::INSERT_KNOWN_DISPLAY, DatabaseParams::new(vec![
+        DatabaseParam::String(display_id),
+        DatabaseParam::String(name),
+        DatabaseParam::String(address),
+        DatabaseParam::Usize(last_seen)
+    ])).await?;
+    Ok(())
+}
+
+/// Every display this controller has reached, most recently seen first
+pub async fn select_known_displays(database: DataLink) -> Res<Vec<KnownDisplay>> {
+    Ok(
+        database.query_map(sql::SELECT_KNOWN_DISPLAYS, DatabaseParams::empty())
+            .await?
+            .into_iter()
+            .filter_map(parse_row_into_known_display)
+            .collect()
+    )
+}
+
+/// Find where a display was last reached
+pub async fn select_known_display(database: DataLink, display_id: String) -> Res<Option<KnownDisplay>> {
+    Ok(
+        database.query_map(sql::SELECT_KNOWN_DISPLAY_BY_DISPLAY_ID, DatabaseParams::single(DatabaseParam::String(display_id)))
+            .await?
+            .into_iter()
+            .filter_map(parse_row_into_known_display)
+            .next()
+    )
+}
+
+/// Forget a display, its pairing is kept
+pub async fn delete_known_display(database: DataLink, display_id: String) -> Res<()> {
+    database.execute_and_wait(sql::DELETE_KNOWN_DISPLAY, DatabaseParams::single(DatabaseParam::String(display_id))).await?;
+    Ok(())
+}
+
+pub fn parse_row_into_known_display(row: Vec<DatabaseParam>) -> Option<KnownDisplay> {
+    let mut iterator = row.into_iter();
+    let display_id = iterator.next()?.string();
+    let name = iterator.next()?.string();
+    let address = iterator.next()?.string();
+    let last_seen = iterator.next()?.usize();
+
+    Some(KnownDisplay {
+        display_id, name, address, last_seen
+    })
+}
@@ -0,0 +1,203 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite_async::database::{DataLink, DatabaseParam, DatabaseParams};
+
+use crate::{database::{interface::DatabaseInterfaceError, sql}, error::Res};
+
+/// One step from the previous schema version to `version`, applied in a single transaction.
+pub struct Migration {
+    pub version: usize,
+    pub description: &'static str,
+    pub statements: &'static [&'static str]
+}
+
+/// Every migration, oldest first. Append to this list, never edit or reorder a migration that has shipped.
+pub const MIGRATIONS: &[Migration] = &[
+    // Uses IF NOT EXISTS, so databases created before versioning adopt it unchanged
+    Migration {
+        version: 1,
+        description: "Create the initial tables",
+        statements: &[
+            sql::CREATE_TOKEN_TABLE,
+            sql::CREATE_ALBUM_TABLE,
+            sql::CREATE_PHOTO_TABLE,
+            sql::CREATE_ENTRY_TABLE,
+            sql::CREATE_SETTINGS_TABLE,
+            sql::CREATE_CONTROLLER_TABLE,
+            sql::CREATE_PAIRING_TABLE,
+            sql::CREATE_CONTROLLER_ROLE_TABLE,
+            sql::CREATE_KNOWN_DISPLAY_TABLE
+        ]
+    },
+    Migration {
+        version: 2,
+        description: "Index album entries by photo",
+        statements: &[
+            sql::CREATE_ENTRY_PHOTO_INDEX
+        ]
+    },
+    Migration {
+        version: 3,
+        description: "Store the OneDrive eTag and cTag of photos",
+        statements: &[
+            sql::ADD_PHOTO_ETAG_COLUMN,
+            sql::ADD_PHOTO_CTAG_COLUMN
+        ]
+    },
+    Migration {
+        version: 4,
+        description: "Store missing photo dates and locations as NULL, and dates as integers",
+        statements: &[
+            sql::CREATE_TYPED_PHOTO_TABLE,
+            sql::COPY_INTO_TYPED_PHOTO_TABLE,
+            sql::DROP_PHOTO_TABLE,
+            sql::RENAME_TYPED_PHOTO_TABLE
+        ]
+    }
+];
+
+/// Bring the database up to the newest schema, returning the version it is now at.
+/// Must finish before anything else uses the database, as the database thread would run their statements inside a migration's transaction.
+pub async fn migrate(database: DataLink) -> Res<usize> {
+    database.execute_and_wait(sql::CREATE_SCHEMA_VERSION_TABLE, DatabaseParams::empty()).await?;
+    let current = schema_version(database.clone()).await?;
+
+    let latest = MIGRATIONS.last().map(|migration| migration.version).unwrap_or_default();
+    if current > latest {
+        return Err(DatabaseInterfaceError::SchemaTooNew(current).into());
+    }
+
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+        if let Err(error) = apply(database.clone(), migration).await {
+            // Nothing of the migration is kept, the database stays at the version before it
+            let _ = database.execute_and_wait(sql::ROLLBACK_TRANSACTION, DatabaseParams::empty()).await;
+            return Err(DatabaseInterfaceError::MigrationFailed(migration.version, format!("{}: {error:?}", migration.description)).into());
+        }
+    }
+
+    Ok(latest)
+}
+
+/// `migrate` for the constructors of the graphical applications, which cannot await.
+pub fn migrate_blocking(database: DataLink) -> Res<usize> {
+    iced::futures::executor::block_on(migrate(database))
+}
+
+/// The newest migration applied, 0 for a database that has never been migrated
+pub async fn schema_version(database: DataLink) -> Res<usize> {
+    let rows = database.query_map(sql::SELECT_SCHEMA_VERSION, DatabaseParams::empty()).await?;
+    let row = rows.first().ok_or(DatabaseInterfaceError::IncorrectNumberOfRows)?;
+    match row.first() {
+        Some(DatabaseParam::Usize(version)) => Ok(*version),
+        _ => Err(DatabaseInterfaceError::MalformedRow.into())
+    }
+}
+
+async fn apply(database: DataLink, migration: &Migration) -> Res<()> {
+    let applied_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as usize;
+
+    database.execute_and_wait(sql::BEGIN_TRANSACTION, DatabaseParams::empty()).await?;
+    for statement in migration.statements {
+        database.execute_and_wait(statement, DatabaseParams::empty()).await?;
+    }
+    database.execute_and_wait(sql::INSERT_SCHEMA_VERSION, DatabaseParams::new(vec![
+        DatabaseParam::Usize(migration.version),
+        DatabaseParam::String(migration.description.to_string()),
+        DatabaseParam::Usize(applied_at)
+    ])).await?;
+    database.execute_and_wait(sql::COMMIT_TRANSACTION, DatabaseParams::empty()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::interface::{select_photo_by_id, upsert_photo};
+    use crate::database::scratch::ScratchDatabase;
+    use crate::onedrive::get_album_children::{ItemTags, LocationData, Photo};
+
+    // Version 3 wrote a missing date as NONE, a missing location as 0, 0, 0 and a missing altitude as 0
+    const VERSION_3_PHOTOS: &str = "
+        INSERT INTO Photos (onedrive_id, name, creation_date, width, height, filesize, latitude, longitude, altitude, etag, ctag) VALUES
+            ('undated', 'undated.jpg', 'NONE', 640, 480, 1024, 0, 0, 0, NULL, NULL),
+            ('greenwich', 'greenwich.jpg', '1700000000', 640, 480, 2048, 51.4779, 0, 0, 'etag', 'ctag'),
+            ('sydney', 'sydney.jpg', '1700000001', 640, 480, 4096, -33.8568, 151.2153, 58.5, NULL, NULL);
+    ";
+
+    /// A database as version 3 left it, holding rows the way version 3 wrote them.
+    async fn version_3() -> ScratchDatabase {
+        let scratch = ScratchDatabase::empty();
+        let database = scratch.derive();
+
+        database.execute_and_wait(sql::CREATE_SCHEMA_VERSION_TABLE, DatabaseParams::empty()).await.unwrap();
+        for migration in MIGRATIONS.iter().take_while(|migration| migration.version <= 3) {
+            apply(database.clone(), migration).await.unwrap();
+        }
+        database.execute_and_wait(VERSION_3_PHOTOS, DatabaseParams::empty()).await.unwrap();
+
+        scratch
+    }
+
+    async fn stored(database: DataLink, onedrive_id: &str) -> Photo {
+        select_photo_by_id(database, onedrive_id.to_string()).await.unwrap().unwrap()
+    }
+
+    fn location(photo: &Photo) -> Option<(f64, f64, Option<f64>)> {
+        photo.location.as_ref().map(|location| (location.latitude, location.longitude, location.altitude))
+    }
+
+    #[tokio::test]
+    async fn placeholders_of_version_3_become_null() {
+        let scratch = version_3().await;
+        let database = scratch.derive();
+        assert_eq!(schema_version(database.clone()).await.unwrap(), 3);
+
+        let latest = migrate(database.clone()).await.unwrap();
+        assert_eq!(schema_version(database.clone()).await.unwrap(), latest);
+
+        let undated = stored(database.clone(), "undated").await;
+        assert_eq!(undated.creation_date, None);
+        assert_eq!(location(&undated), None);
+
+        // Only both coordinates at 0 meant no location, a longitude of 0 on its own is real
+        let greenwich = stored(database.clone(), "greenwich").await;
+        assert_eq!(greenwich.creation_date, Some(1_700_000_000));
+        assert_eq!(location(&greenwich), Some((51.4779, 0.0, None)));
+
+        let sydney = stored(database.clone(), "sydney").await;
+        assert_eq!(sydney.creation_date, Some(1_700_000_001));
+        assert_eq!(location(&sydney), Some((-33.8568, 151.2153, Some(58.5))));
+        assert_eq!((sydney.width, sydney.height, sydney.filesize), (640, 480, 4096));
+    }
+
+    #[tokio::test]
+    async fn missing_and_zero_values_round_trip() {
+        let scratch = ScratchDatabase::migrated().await;
+        let database = scratch.derive();
+
+        let photo = |onedrive_id: &str, creation_date, location| Photo {
+            id: 0,
+            onedrive_id: onedrive_id.to_string(),
+            name: format!("{onedrive_id}.jpg"),
+            creation_date,
+            width: 640,
+            height: 480,
+            filesize: 1024,
+            location
+        };
+
+        let (missing, _) = upsert_photo(database.clone(), photo("missing", None, None), ItemTags::default()).await.unwrap();
+        assert_eq!(missing.creation_date, None);
+        assert_eq!(location(&missing), None);
+
+        // Now that missing values are NULL, zeroes are stored as the values they are
+        let null_island = LocationData { altitude: Some(0.0), latitude: 0.0, longitude: 0.0 };
+        let (zero, _) = upsert_photo(database.clone(), photo("zero", Some(0), Some(null_island)), ItemTags::default()).await.unwrap();
+        assert_eq!(zero.creation_date, Some(0));
+        assert_eq!(location(&zero), Some((0.0, 0.0, Some(0.0))));
+
+        let unknown_altitude = LocationData { altitude: None, latitude: 0.0, longitude: -0.5 };
+        let (ground, _) = upsert_photo(database.clone(), photo("ground", Some(1), Some(unknown_altitude)), ItemTags::default()).await.unwrap();
+        assert_eq!(location(&ground), Some((0.0, -0.5, None)));
+    }
+}
@@ -1,2 +1,5 @@
 pub mod interface;
+pub mod migrations;
+#[cfg(test)]
+pub mod scratch;
 mod sql;
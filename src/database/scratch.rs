@@ -0,0 +1,40 @@
+use std::path::PathBuf;
+
+use rand::{Rng, rng};
+use rusqlite_async::database::{DataLink, Database};
+
+use crate::database::migrations;
+
+/// A database in a directory of its own under the system's temporary directory, removed when dropped. Only for tests.
+pub struct ScratchDatabase {
+    database: Database,
+    root: PathBuf
+}
+
+impl ScratchDatabase {
+    /// A database that has never been migrated.
+    pub fn empty() -> ScratchDatabase {
+        let root = std::env::temp_dir().join(format!("reflection-test-{:016x}", rng().random::<u64>()));
+        std::fs::create_dir_all(&root).unwrap();
+
+        let (database, _) = Database::new(root.clone());
+        ScratchDatabase { database, root }
+    }
+
+    /// A database at the newest schema.
+    pub async fn migrated() -> ScratchDatabase {
+        let scratch = ScratchDatabase::empty();
+        migrations::migrate(scratch.derive()).await.unwrap();
+        scratch
+    }
+
+    pub fn derive(&self) -> DataLink {
+        self.database.derive()
+    }
+}
+
+impl Drop for ScratchDatabase {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
@@ -50,7 +50,7 @@ pub const SELECT_TOKEN: &str = "
     SELECT token, expiration FROM Tokens ORDER BY id DESC LIMIT 1;
 ";
 
-pub const INSERT_PHOTO: &str = "
+pub const UPSERT_PHOTO: &str = "
     INSERT INTO Photos (
         id,
         onedrive_id,
@@ -61,7 +61,9 @@ pub const INSERT_PHOTO: &str = "
         filesize,
         latitude,
         longitude,
-        altitude
+        altitude,
+        etag,
+        ctag
     ) VALUES (
         null,
         ?,
@@ -72,11 +74,24 @@ pub const INSERT_PHOTO: &str = "
         ?,
         ?,
         ?,
+        ?,
+        ?,
         ?
-    );
+    )
+    ON CONFLICT (onedrive_id) DO UPDATE SET
+        name = excluded.name,
+        creation_date = excluded.creation_date,
+        width = excluded.width,
+        height = excluded.height,
+        filesize = excluded.filesize,
+        latitude = excluded.latitude,
+        longitude = excluded.longitude,
+        altitude = excluded.altitude,
+        etag = excluded.etag,
+        ctag = excluded.ctag;
 ";
 
-pub const INSERT_ALBUM: &str = "
+pub const UPSERT_ALBUM: &str = "
     INSERT INTO Albums (
         id,
         onedrive_id,
@@ -87,7 +102,10 @@ pub const INSERT_ALBUM: &str = "
         ?,
         ?,
         ?
-    );
+    )
+    ON CONFLICT (onedrive_id) DO UPDATE SET
+        name = excluded.name,
+        share_link = excluded.share_link;
 ";
 
 pub const INSERT_ENTRY: &str = "
@@ -116,26 +134,307 @@ pub const DELETE_ALBUM_BY_ID: &str = "
     WHERE id = ?;
 ";
 
-pub const SELECT_PHOTOS_BY_ALBUM_ID: &str = "
-    SELECT Photos.*
+pub const DELETE_ENTRIES_BY_ALBUM_ID: &str = "
+    DELETE FROM Entries
+    WHERE album_id = ?;
+";
+
+pub const SELECT_ENTRY_COUNT_BY_PHOTO_ID: &str = "
+    SELECT COUNT(*) FROM Entries WHERE photo_id = ?;
+";
+
+// Columns every photo and album query selects, in this order. Rows are parsed by the names in PHOTO_COLUMNS and ALBUM_COLUMNS,
+// so the two lists of each pair have to be kept the same.
+macro_rules! photo_columns {
+    () => { "Photos.id, Photos.onedrive_id, Photos.name, Photos.creation_date, Photos.width, Photos.height, Photos.filesize, Photos.latitude, Photos.longitude, Photos.altitude" };
+}
+
+pub const PHOTO_COLUMNS: &[&str] = &["id", "onedrive_id", "name", "creation_date", "width", "height", "filesize", "latitude", "longitude", "altitude"];
+
+macro_rules! album_columns {
+    () => { "Albums.id, Albums.onedrive_id, Albums.name, Albums.share_link" };
+}
+
+pub const ALBUM_COLUMNS: &[&str] = &["id", "onedrive_id", "name", "share_link"];
+
+pub const SELECT_PHOTOS_BY_ALBUM_ID: &str = concat!("
+    SELECT ", photo_columns!(), "
     FROM Photos
     INNER JOIN Entries ON Entries.photo_id = Photos.id
     WHERE Entries.album_id = ?;
-";
+");
 
-pub const SELECT_ALL_ALBUMS: &str = "
-    SELECT *
+pub const SELECT_ALL_ALBUMS: &str = concat!("
+    SELECT ", album_columns!(), "
     FROM Albums;
+");
+
+pub const SELECT_ALBUM_BY_ID: &str = concat!("
+    SELECT ", album_columns!(), " FROM Albums WHERE id = ?;
+");
+
+pub const SELECT_ALBUM_BY_ONEDRIVE_ID: &str = concat!("
+    SELECT ", album_columns!(), " FROM Albums WHERE onedrive_id = ?;
+");
+
+pub const SELECT_PHOTO_BY_ONEDRIVE_ID: &str = concat!("
+    SELECT ", photo_columns!(), " FROM Photos WHERE onedrive_id = ?;
+");
+
+pub const SELECT_PHOTO_CTAG_BY_ONEDRIVE_ID: &str = "
+    SELECT ctag FROM Photos WHERE onedrive_id = ?;
+";
+
+pub const SELECT_ALBUMS_BY_PHOTO_ID: &str = concat!("
+    SELECT ", album_columns!(), "
+    FROM Albums
+    INNER JOIN Entries ON Entries.album_id = Albums.id
+    WHERE Entries.photo_id = ?;
+");
+
+pub const CREATE_SETTINGS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS Settings (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+";
+
+pub const CREATE_CONTROLLER_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS Controllers (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        controller_id TEXT UNIQUE,
+        key TEXT NOT NULL,
+        paired_at INTEGER NOT NULL
+    );
+";
+
+pub const CREATE_PAIRING_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS Pairings (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        display_id TEXT UNIQUE,
+        controller_id TEXT NOT NULL,
+        key TEXT NOT NULL
+    );
+";
+
+// Controllers paired before roles existed have no row, and keep the full control they had
+pub const CREATE_CONTROLLER_ROLE_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS ControllerRoles (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        controller_id TEXT UNIQUE,
+        role TEXT NOT NULL,
+        expires_at INTEGER
+    );
+";
+
+pub const CREATE_KNOWN_DISPLAY_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS KnownDisplays (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        display_id TEXT UNIQUE,
+        name TEXT NOT NULL,
+        address TEXT NOT NULL,
+        last_seen INTEGER NOT NULL
+    );
+";
+
+pub const INSERT_SETTING: &str = "
+    INSERT OR REPLACE INTO Settings (
+        key,
+        value
+    ) VALUES (
+        ?,
+        ?
+    );
+";
+
+pub const SELECT_SETTING: &str = "
+    SELECT value FROM Settings WHERE key = ?;
+";
+
+pub const INSERT_CONTROLLER: &str = "
+    INSERT INTO Controllers (
+        id,
+        controller_id,
+        key,
+        paired_at
+    ) VALUES (
+        null,
+        ?,
+        ?,
+        ?
+    );
+";
+
+pub const SELECT_CONTROLLER_KEY: &str = "
+    SELECT key FROM Controllers WHERE controller_id = ?;
+";
+
+pub const INSERT_PAIRING: &str = "
+    INSERT OR REPLACE INTO Pairings (
+        id,
+        display_id,
+        controller_id,
+        key
+    ) VALUES (
+        null,
+        ?,
+        ?,
+        ?
+    );
+";
+
+pub const SELECT_PAIRING_BY_DISPLAY_ID: &str = "
+    SELECT display_id, controller_id, key FROM Pairings WHERE display_id = ?;
+";
+
+pub const INSERT_CONTROLLER_ROLE: &str = "
+    INSERT OR REPLACE INTO ControllerRoles (
+        id,
+        controller_id,
+        role,
+        expires_at
+    ) VALUES (
+        null,
+        ?,
+        ?,
+        ?
+    );
+";
+
+pub const SELECT_CONTROLLERS: &str = "
+    SELECT Controllers.controller_id, Controllers.paired_at, ControllerRoles.role, ControllerRoles.expires_at
+    FROM Controllers LEFT JOIN ControllerRoles ON Controllers.controller_id = ControllerRoles.controller_id
+    ORDER BY Controllers.paired_at;
+";
+
+pub const SELECT_CONTROLLER_BY_CONTROLLER_ID: &str = "
+    SELECT Controllers.controller_id, Controllers.paired_at, ControllerRoles.role, ControllerRoles.expires_at
+    FROM Controllers LEFT JOIN ControllerRoles ON Controllers.controller_id = ControllerRoles.controller_id
+    WHERE Controllers.controller_id = ?;
+";
+
+pub const INSERT_KNOWN_DISPLAY: &str = "
+    INSERT OR REPLACE INTO KnownDisplays (
+        id,
+        display_id,
+        name,
+        address,
+        last_seen
+    ) VALUES (
+        null,
+        ?,
+        ?,
+        ?,
+        ?
+    );
+";
+
+pub const SELECT_KNOWN_DISPLAYS: &str = "
+    SELECT display_id, name, address, last_seen FROM KnownDisplays ORDER BY last_seen DESC;
+";
+
+pub const SELECT_KNOWN_DISPLAY_BY_DISPLAY_ID: &str = "
+    SELECT display_id, name, address, last_seen FROM KnownDisplays WHERE display_id = ?;
+";
+
+pub const DELETE_KNOWN_DISPLAY: &str = "
+    DELETE FROM KnownDisplays WHERE display_id = ?;
+";
+
+// Does nothing, answered once every statement queued before it has run
+pub const BARRIER: &str = "
+    SELECT 1;
+";
+
+pub const CREATE_SCHEMA_VERSION_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS SchemaVersions (
+        version INTEGER PRIMARY KEY,
+        description TEXT NOT NULL,
+        applied_at INTEGER NOT NULL
+    );
+";
+
+pub const SELECT_SCHEMA_VERSION: &str = "
+    SELECT COALESCE(MAX(version), 0) FROM SchemaVersions;
+";
+
+pub const INSERT_SCHEMA_VERSION: &str = "
+    INSERT INTO SchemaVersions (
+        version,
+        description,
+        applied_at
+    ) VALUES (
+        ?,
+        ?,
+        ?
+    );
+";
+
+pub const BEGIN_TRANSACTION: &str = "
+    BEGIN IMMEDIATE;
+";
+
+pub const COMMIT_TRANSACTION: &str = "
+    COMMIT;
+";
+
+pub const ROLLBACK_TRANSACTION: &str = "
+    ROLLBACK;
+";
+
+pub const CREATE_ENTRY_PHOTO_INDEX: &str = "
+    CREATE INDEX IF NOT EXISTS EntriesByPhoto ON Entries (photo_id);
+";
+
+pub const ADD_PHOTO_ETAG_COLUMN: &str = "
+    ALTER TABLE Photos ADD COLUMN etag TEXT;
 ";
 
-pub const SELECT_ALL_PHOTOS: &str = "
-    SELECT * FROM Photos;
+pub const ADD_PHOTO_CTAG_COLUMN: &str = "
+    ALTER TABLE Photos ADD COLUMN ctag TEXT;
+";
+
+// Rebuilds Photos, as SQLite cannot change the type of a column. Missing dates were stored as the text NONE,
+// a missing location as 0 for latitude, longitude and altitude, and a missing altitude as 0.
+pub const CREATE_TYPED_PHOTO_TABLE: &str = "
+    CREATE TABLE TypedPhotos (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        onedrive_id TEXT UNIQUE,
+        name TEXT NOT NULL,
+        creation_date INTEGER,
+        width INTEGER NOT NULL,
+        height INTEGER NOT NULL,
+        filesize INTEGER NOT NULL,
+        latitude REAL,
+        longitude REAL,
+        altitude REAL,
+        etag TEXT,
+        ctag TEXT
+    );
+";
+
+pub const COPY_INTO_TYPED_PHOTO_TABLE: &str = "
+    INSERT INTO TypedPhotos
+    SELECT
+        id,
+        onedrive_id,
+        name,
+        CASE WHEN creation_date = 'NONE' THEN NULL ELSE CAST(creation_date AS INTEGER) END,
+        width,
+        height,
+        filesize,
+        CASE WHEN latitude = 0 AND longitude = 0 THEN NULL ELSE latitude END,
+        CASE WHEN latitude = 0 AND longitude = 0 THEN NULL ELSE longitude END,
+        CASE WHEN (latitude = 0 AND longitude = 0) OR altitude = 0 THEN NULL ELSE altitude END,
+        etag,
+        ctag
+    FROM Photos;
 ";
 
-pub const SELECT_ALBUM_BY_ID: &str = "
-    SELECT * FROM Albums WHERE id = ?;
+pub const DROP_PHOTO_TABLE: &str = "
+    DROP TABLE Photos;
 ";
 
-pub const SELECT_PHOTO_BY_ID: &str = "
-    SELECT * FROM Photos WHERE id = ?;
+pub const RENAME_TYPED_PHOTO_TABLE: &str = "
+    ALTER TABLE TypedPhotos RENAME TO Photos;
 ";
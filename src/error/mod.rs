@@ -7,6 +7,8 @@ use rand::rand_core::OsError;
 use tokio::task::JoinError;
 
 use crate::authentication::callback::server::ServerError;
+use crate::communication::secure::SecureChannelError;
+use crate::frontend::command_line::CommandLineError;
 use crate::database::interface::DatabaseInterfaceError;
 use crate::directories::create::DirectoryError;
 use crate::authentication::oauth2::api::OAUTH2ApiError;
@@ -21,7 +23,7 @@ type ReqwestError = reqwest::Error;
 type SerdeJsonError = serde_json::Error;
 type DatabaseError = rusqlite_async::error::Error;
 type RancorError = rkyv::rancor::Error;
-type UdpError = udp_discovery::error::Error;
+type MdnsError = mdns_sd::Error;
 
 macro_rules! error_enum {
     (
@@ -94,6 +96,8 @@ error_enum! {
         DownloadError,
         ApplicationError,
         RancorError,
-        UdpError,
+        SecureChannelError,
+        CommandLineError,
+        MdnsError,
     }
 }
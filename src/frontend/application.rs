@@ -4,7 +4,8 @@ use rusqlite_async::database::Database;
 
 use crate::authentication::oauth2::api::TokenSet;
 use crate::authentication::oauth2::wrapper::authenticate;
-use crate::database::interface;
+use crate::communication::roles::Role;
+use crate::database::{interface, migrations};
 use crate::directories::create::Directories;
 use crate::error::Error;
 use crate::frontend::message::Global;
@@ -24,9 +25,43 @@ use crate::onedrive::get_drive::DriveData;
 pub enum ApplicationError {
     NotAuthenticated,
     NoSuchAlbum,
+    NoSuchPhoto,
     EndianFailure,
     NoEndpoint,
-    NotConnected
+    NotConnected,
+    PeerTimedOut,
+
+    // Correlated requests, by request id
+    RequestTimedOut(u64),
+    RequestFailed(u64, String),
+    UnexpectedReply,
+    // Frames refused from a peer, which is then disconnected: one that could not be unpacked by the agreed codec,
+    // one larger than the configured limit (its size in bytes) and one not matching its checksum
+    MalformedFrame,
+    FrameTooLarge(usize),
+    FrameCorrupted,
+    // A streamed file arrived out of order or did not match its digest, by request id
+    TransferCorrupted(u64),
+
+    // Roles of paired controllers
+    PermissionDenied(Role),
+    NoSuchController,
+    LastOwner,
+    // A guest PIN was asked to last longer than a guest may stay, by minutes
+    GuestLifetimeTooLong(u64),
+
+    // Protocol handshake
+    NotReflectionPeer,
+    HandshakeTimeout,
+    // A length prefixed handshake field did not fit its single length byte, by length
+    FieldTooLong(usize),
+    // (local version, remote version)
+    IncompatibleProtocol(u16, u16),
+    // The peers share no wire encoding
+    NoCommonCodec,
+
+    // The HTTP API could not be served, with the reason
+    ApiUnavailable(String)
 }
 
 pub struct Application {
@@ -51,7 +86,10 @@ impl Application {
         let directories = Directories::create_or_load().expect("[CRITICAL ERROR] Unable to find suitable directories location.");
         let (database, error_handle) = Database::new(directories.root.clone());
 
-        interface::create_tables(database.derive()).expect("[CRITICAL ERROR] Unable to create database tables.");
+        if let Err(error) = migrations::migrate_blocking(database.derive()) {
+            eprintln!("[CRITICAL ERROR] Unable to migrate the database: {error:?}");
+            std::process::exit(1);
+        }
 
         Self {
             database,
@@ -116,10 +154,11 @@ impl Application {
                                 let access_token = tokenset.access_token.clone();
                                 let drive_id = drivedata.id.clone();
                                 let datalink = self.database.derive();
-                                Task::future(new_album(AccessToken::new(access_token), drive_id, sharelink, datalink))
+                                let album_root_dir = self.directories.albums.clone();
+                                Task::future(new_album(AccessToken::new(access_token), drive_id, sharelink, datalink, album_root_dir))
                                     .then(|res|
                                         match res {
-                                            Ok((album, contents)) => {
+                                            Ok((album, contents, _)) => {
                                                 Task::batch(vec![
                                                     Task::done(SelectAlbumMessage::AddAlbum(album.clone()).into()),
                                                     Task::done(BrowseAlbumMessage::Display(album, contents).into()),
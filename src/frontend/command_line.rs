@@ -0,0 +1,258 @@
+use std::fs::{read_to_string, write};
+use std::io::{Read, stdin};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_channel::{Receiver, unbounded};
+use rusqlite_async::database::Database;
+use serde_json::{Value, json};
+
+use crate::authentication::oauth2::api::TokenSet;
+use crate::communication::NetworkMessage;
+use crate::communication::client::{Client, REQUEST_TIMEOUT};
+use crate::communication::discovery::Group;
+use crate::communication::heartbeat::Heartbeat;
+use crate::communication::roles::{Controller, Role};
+use crate::communication::transfer::TransferProgress;
+use crate::database::migrations;
+use crate::directories::create::Directories;
+use crate::error::{Error, Res};
+use crate::frontend::application::ApplicationError;
+use crate::onedrive::get_album_children::{Album, Photo};
+
+/// Adding a share link downloads the album's listing, which takes far longer than answering a request.
+const SHARELINK_TIMEOUT: Duration = Duration::from_secs(120);
+
+const USAGE: &str = "usage: reflection ctl [--group NAME] [--port PORT] [--display ID] [--pin PIN] (displays | albums | active | photos ALBUM | fetch ALBUM PHOTO FILE | play ALBUM | add SHARELINK | tokenset FILE|- | controllers | role CONTROLLER owner|viewer|guest | guest MINUTES)";
+
+#[derive(Clone, Debug)]
+pub enum CommandLineError {
+    Usage(&'static str),
+    NoDisplays,
+    // Several displays answered and none was chosen, with the ids found
+    AmbiguousDisplay(Vec<String>),
+    NoSuchAlbum(String),
+    NoSuchPhoto(String),
+    Interrupted
+}
+
+struct Options {
+    group: Group,
+    display_id: Option<String>,
+    pin: Option<String>,
+    command: Vec<String>
+}
+
+/// Run `reflection ctl`. Prints a single JSON document on stdout, or a JSON error on stderr, and returns the exit code.
+pub fn run(arguments: &[String]) -> i32 {
+    let result = tokio::runtime::Runtime::new()
+        .map_err(Error::from)
+        .and_then(|runtime| runtime.block_on(execute(arguments)));
+
+    match result {
+        Ok(output) => {
+            println!("{output}");
+            0
+        },
+        Err(e) => {
+            eprintln!("{}", json!({ "error": format!("{e:?}") }));
+            1
+        }
+    }
+}
+
+fn parse(arguments: &[String]) -> Res<Options> {
+    let mut options = Options { group: Group::default(), display_id: None, pin: None, command: Vec::new() };
+    let mut arguments = arguments.iter();
+
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--group" => options.group.identifier = arguments.next().ok_or(CommandLineError::Usage(USAGE))?.clone(),
+            "--port" => options.group.port = arguments.next().and_then(|port| port.parse().ok()).ok_or(CommandLineError::Usage(USAGE))?,
+            "--display" => options.display_id = Some(arguments.next().ok_or(CommandLineError::Usage(USAGE))?.clone()),
+            "--pin" => options.pin = Some(arguments.next().ok_or(CommandLineError::Usage(USAGE))?.clone()),
+            _ => options.command.push(argument.clone())
+        }
+    }
+
+    Ok(options)
+}
+
+async fn execute(arguments: &[String]) -> Res<Value> {
+    let options = parse(arguments)?;
+    let command: Vec<&str> = options.command.iter().map(String::as_str).collect();
+
+    if let ["displays"] = command.as_slice() {
+        return Ok(json!(Client::discover(options.group).await?));
+    }
+
+    let directories = Directories::create_or_load()?;
+    let (database, _database_errors) = Database::new(directories.root.clone());
+    migrations::migrate(database.derive()).await?;
+
+    let display_id = match options.display_id {
+        Some(display_id) => display_id,
+        None => only_display(options.group.clone()).await?
+    };
+
+    let (client, receiver) = Client::spawn(database.derive(), options.group, display_id, options.pin, Heartbeat::default()).await?;
+    let client = Arc::new(client);
+
+    match command.as_slice() {
+        ["albums"] => Ok(json!(all_albums(client).await?)),
+
+        ["active"] => Ok(json!(active_album(client).await?)),
+
+        ["photos", album] => {
+            let album = find_album(client.clone(), album).await?;
+            Ok(json!(photos_in_album(client, album).await?))
+        },
+
+        ["fetch", album, wanted, path] => {
+            let album = find_album(client.clone(), album).await?;
+            let photo = photos_in_album(client.clone(), album.clone())
+                .await?
+                .into_iter()
+                .find(|photo| photo.id.to_string() == *wanted || photo.onedrive_id == *wanted || photo.name == *wanted)
+                .ok_or(CommandLineError::NoSuchPhoto(wanted.to_string()))?;
+
+            let (progress_sender, progress_receiver) = unbounded::<TransferProgress>();
+            let fetch = client.clone().fetch_photo(album, photo, progress_sender);
+            tokio::pin!(fetch);
+            let mut transfer = None;
+
+            let bytes = loop {
+                tokio::select! {
+                    bytes = &mut fetch => break bytes?,
+
+                    // Progress goes to stderr as one JSON object per line, keeping stdout a single document
+                    Ok(progress) = progress_receiver.recv() => {
+                        transfer = Some(progress.id);
+                        eprintln!("{}", json!({ "received": progress.received, "total": progress.total }));
+                    }
+
+                    // The display answers the cancellation, which ends the fetch with an error
+                    _ = tokio::signal::ctrl_c() => match transfer {
+                        Some(id) => Client::cancel_transfer(client.yield_sender(), id).await?,
+                        None => return Err(CommandLineError::Interrupted.into())
+                    }
+                }
+            };
+
+            write(path, &bytes)?;
+            Ok(json!({ "path": path, "bytes": bytes.len() }))
+        },
+
+        ["play", wanted] => {
+            let album = find_album(client.clone(), wanted).await?;
+
+            Client::send_with(client.yield_sender(), NetworkMessage::PlayAlbum(album)).await?;
+
+            // The display handles messages in order, so the answer reflects the album just played
+            Ok(json!(active_album(client).await?))
+        },
+
+        // The display answers once the album is synced, or with the reason it could not be
+        ["add", sharelink] => match client.request(|id| NetworkMessage::Sharelink(id, sharelink.to_string()), SHARELINK_TIMEOUT).await? {
+            NetworkMessage::ReturnNewAlbum(_, album) => Ok(json!(album)),
+            _ => Err(ApplicationError::UnexpectedReply.into())
+        },
+
+        ["tokenset", path] => {
+            let tokenset: TokenSet = serde_json::from_str(&read_input(path)?)?;
+            Client::send_with(client.yield_sender(), NetworkMessage::TokenSet(tokenset)).await?;
+
+            // Wait for a round trip so the token set has left before the process exits, and any refusal of it has arrived
+            active_album(client).await?;
+            refused(&receiver)?;
+            Ok(json!({ "sent": true }))
+        },
+
+        ["controllers"] => Ok(json!(controllers(client, NetworkMessage::RequestControllers).await?)),
+
+        ["role", controller_id, role] => {
+            let role = Role::parse(role).ok_or(CommandLineError::Usage(USAGE))?;
+            Ok(json!(controllers(client, |id| NetworkMessage::SetRole(id, controller_id.to_string(), role)).await?))
+        },
+
+        ["guest", minutes] => {
+            let minutes: u64 = minutes.parse().map_err(|_| CommandLineError::Usage(USAGE))?;
+            match client.request(|id| NetworkMessage::RequestGuestPin(id, minutes), REQUEST_TIMEOUT).await? {
+                NetworkMessage::ReturnGuestPin(_, pin, expires_at) => Ok(json!({ "pin": pin, "expires_at": expires_at })),
+                _ => Err(ApplicationError::UnexpectedReply.into())
+            }
+        },
+
+        _ => Err(CommandLineError::Usage(USAGE).into())
+    }
+}
+
+/// Pick the display when exactly one answers, so `--display` can be left out on a simple network.
+async fn only_display(group: Group) -> Res<String> {
+    let mut displays = Client::discover(group).await?;
+
+    match displays.len() {
+        0 => Err(CommandLineError::NoDisplays.into()),
+        1 => Ok(displays.remove(0).announcement.display_id),
+        _ => Err(CommandLineError::AmbiguousDisplay(displays.into_iter().map(|display| display.announcement.display_id).collect()).into())
+    }
+}
+
+async fn all_albums(client: Arc<Client>) -> Res<Vec<Album>> {
+    match client.request(NetworkMessage::RequestAllAlbums, REQUEST_TIMEOUT).await? {
+        NetworkMessage::ReturnAllAlbums(_, albums) => Ok(albums),
+        _ => Err(ApplicationError::UnexpectedReply.into())
+    }
+}
+
+async fn find_album(client: Arc<Client>, wanted: &str) -> Res<Album> {
+    all_albums(client)
+        .await?
+        .into_iter()
+        .find(|album| album.id.to_string() == wanted || album.onedrive_id == wanted || album.name == wanted)
+        .ok_or(CommandLineError::NoSuchAlbum(wanted.to_string()).into())
+}
+
+async fn photos_in_album(client: Arc<Client>, album: Album) -> Res<Vec<Photo>> {
+    match client.request(|id| NetworkMessage::RequestPhotosInAlbum(id, album), REQUEST_TIMEOUT).await? {
+        NetworkMessage::ReturnPhotosInAlbum(_, _, photos) => Ok(photos),
+        _ => Err(ApplicationError::UnexpectedReply.into())
+    }
+}
+
+async fn active_album(client: Arc<Client>) -> Res<Option<Album>> {
+    match client.request(NetworkMessage::RequestActiveAlbum, REQUEST_TIMEOUT).await? {
+        NetworkMessage::ReturnActiveAlbum(_, album) => Ok(album),
+        _ => Err(ApplicationError::UnexpectedReply.into())
+    }
+}
+
+async fn controllers(client: Arc<Client>, request: impl FnOnce(u64) -> NetworkMessage) -> Res<Vec<Controller>> {
+    match client.request(request, REQUEST_TIMEOUT).await? {
+        NetworkMessage::ReturnControllers(_, controllers) => Ok(controllers),
+        _ => Err(ApplicationError::UnexpectedReply.into())
+    }
+}
+
+/// Fail if the display refused a message sent without a request id, which it answers out of band.
+fn refused(receiver: &Receiver<NetworkMessage>) -> Res<()> {
+    while let Ok(message) = receiver.try_recv() {
+        if let NetworkMessage::PermissionDenied(role) = message {
+            return Err(ApplicationError::PermissionDenied(role).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a file, or standard input when the path is `-`.
+fn read_input(path: &str) -> Res<String> {
+    match path {
+        "-" => {
+            let mut input = String::new();
+            stdin().read_to_string(&mut input)?;
+            Ok(input)
+        },
+        path => Ok(read_to_string(path)?)
+    }
+}
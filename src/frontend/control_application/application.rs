@@ -1,38 +1,178 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 
+use async_channel::Receiver;
+use std::future::Future;
+use rusqlite_async::database::Database;
+
 use iced::advanced::image::Handle;
-use iced::{Background, Length, Task};
-use iced::widget::{Column, Container, MouseArea, Row, Scrollable, Space, Stack, button, image};
+use iced::Task;
+use iced::widget::{Column, Container, MouseArea, Row, Scrollable, Stack, button, text_input};
 use iced::widget::text;
 
-use crate::authentication::oauth2::wrapper::{authenticate, stateless_authentication};
-use crate::communication::client::Client;
-use crate::communication::NetworkMessage;
+use crate::authentication::oauth2::wrapper::stateless_authentication;
+use crate::communication::client::{Client, REQUEST_TIMEOUT};
+use crate::communication::{ConnectionState, DisplayEvent, DisplayState, NetworkMessage, PlaybackState, SyncState};
+use crate::communication::discovery::{DiscoveredDisplay, Group, KnownDisplay};
+use crate::communication::heartbeat::Heartbeat;
+use crate::communication::secure::SecureChannelError;
+use crate::database::{interface, migrations};
+use crate::directories::create::Directories;
+use crate::error::{Error, Res};
 use crate::frontend::application::ApplicationError;
 use crate::frontend::colour::Colour;
 use crate::frontend::control_application::message::Message;
 use crate::onedrive::get_album_children::{Album, Photo};
 use crate::util::relay;
 
-#[derive(Default)]
 pub struct Application {
+    database: Database,
     remote_connection: Option<Arc<Client>>,
     albums: Vec<(Album, Vec<Photo>, bool)>,
     thumbnails: HashMap<String, Handle>,
     active_album: Option<Album>,
-    connection_handle: Option<iced::task::Handle>
+    // Kept current by the display's events once the first snapshot arrives
+    display_state: Option<DisplayState>,
+    connection_handle: Option<iced::task::Handle>,
+    connection_state: Option<ConnectionState>,
+    error: Option<Error>,
+
+    // Displays of our group that answered the last discovery, and the one chosen by the user
+    group: Group,
+    displays: Vec<DiscoveredDisplay>,
+    selected_display: Option<String>,
+    discovering: bool,
+
+    // Displays reached before, and a host name or IP address typed in by hand
+    known_displays: Vec<KnownDisplay>,
+    address: String,
+
+    // PIN shown on the display, only needed the first time this controller connects to it
+    pin: String
 }
 
 impl Application {
+    pub fn new(group: Group) -> (Self, Receiver<rusqlite_async::error::Error>) {
+        let directories = Directories::create_or_load().expect("[CRITICAL ERROR] Unable to find suitable directories location.");
+        let (database, error_receiver) = Database::new(directories.root.clone());
+        if let Err(error) = migrations::migrate_blocking(database.derive()) {
+            eprintln!("[CRITICAL ERROR] Unable to migrate the database: {error:?}");
+            std::process::exit(1);
+        }
+
+        (Self {
+            database,
+            remote_connection: None,
+            albums: Vec::new(),
+            thumbnails: HashMap::new(),
+            active_album: None,
+            display_state: None,
+            connection_handle: None,
+            connection_state: None,
+            error: None,
+            group,
+            displays: Vec::new(),
+            selected_display: None,
+            discovering: false,
+            known_displays: Vec::new(),
+            address: String::new(),
+            pin: String::new()
+        }, error_receiver)
+    }
+
     pub fn view(&self) -> Container<Message> {
         if self.remote_connection.is_none() {
             return Container::new(
                 Column::new()
-                    .push(text("Could not connect to display server."))
+                    .spacing(10)
+                    .padding(10)
+                    .push(match (self.discovering, self.displays.is_empty()) {
+                        (true, _) => text("Searching for displays...").color(Colour::loading()),
+                        (false, true) => text("No displays found."),
+                        (false, false) => text("Choose a display:")
+                    })
+                    .extend(self.displays.iter().map(|display| {
+                        let announcement = &display.announcement;
+                        let status = match (&announcement.status.active_album, announcement.status.authenticated) {
+                            (_, false) => String::from("not authenticated"),
+                            (Some(album), true) => format!("playing {album}"),
+                            (None, true) => String::from("idle")
+                        };
+                        let selected = self.selected_display.as_ref() == Some(&announcement.display_id);
+
+                        button(
+                            Column::new()
+                                .push(text(&announcement.name))
+                                .push(text(format!("{} - {status}, {} controller(s)", display.address, announcement.status.controllers)).color(Colour::loading()))
+                        )
+                        .style(move |theme, status| match selected {
+                            true => button::primary(theme, status),
+                            false => button::secondary(theme, status)
+                        })
+                        .on_press(Message::SelectDisplay(announcement.display_id.clone()))
+                        .into()
+                    }))
+                    .push(
+                        self.known_displays
+                            .iter()
+                            .any(|known| !self.displays.iter().any(|display| display.announcement.display_id == known.display_id))
+                            .then(|| text("Saved displays:"))
+                    )
+                    // Displays that did not answer discovery, reached at the address they were last seen on
+                    .extend(self.known_displays
+                        .iter()
+                        .filter(|known| !self.displays.iter().any(|display| display.announcement.display_id == known.display_id))
+                        .map(|known| {
+                            let selected = self.selected_display.as_ref() == Some(&known.display_id);
+
+                            Row::new()
+                                .spacing(10)
+                                .push(
+                                    button(
+                                        Column::new()
+                                            .push(text(&known.name))
+                                            .push(text(&known.address).color(Colour::loading()))
+                                    )
+                                    .style(move |theme, status| match selected {
+                                        true => button::primary(theme, status),
+                                        false => button::secondary(theme, status)
+                                    })
+                                    .on_press(Message::SelectDisplay(known.display_id.clone()))
+                                )
+                                .push(button("Forget").on_press(Message::ForgetDisplay(known.display_id.clone())))
+                                .into()
+                        })
+                    )
+                    .push(self.error.as_ref().map(|error| text(describe_error(error)).color(Colour::error())))
+                    .push(
+                        text_input("PIN shown on the display (first connection only)", &self.pin)
+                            .on_input(Message::PinInput)
+                            .on_submit(Message::Connect)
+                    )
+                    .push(
+                        Row::new()
+                            .spacing(10)
+                            .push(
+                                text_input("Host name or IP address, for networks that hide displays", &self.address)
+                                    .on_input(Message::AddressInput)
+                                    .on_submit(Message::ConnectAddress)
+                            )
+                            .push(
+                                button("Connect to address")
+                                    .on_press_maybe((!self.address.trim().is_empty()).then_some(Message::ConnectAddress))
+                            )
+                    )
                     .push(
-                        button("Retry?")
-                            .on_press(Message::Connect)
+                        Row::new()
+                            .spacing(10)
+                            .push(
+                                button("Connect")
+                                    .on_press_maybe(self.selected_display.as_ref().map(|_| Message::Connect))
+                            )
+                            .push(
+                                button("Search again")
+                                    .on_press_maybe((!self.discovering).then_some(Message::Discover))
+                            )
                     )
             );
         }
@@ -41,6 +181,45 @@ impl Application {
             Column::new()
                 .spacing(10)
                 .padding(10)
+                .push(
+                    self.connection_state.as_ref().map(|state| match state {
+                        ConnectionState::Connected => text("Connected to display."),
+                        ConnectionState::Connecting => text("Connecting to display...").color(Colour::loading()),
+                        ConnectionState::Lost => text("Connection to display lost.").color(Colour::error()),
+                        ConnectionState::TimedOut => text("The display stopped responding.").color(Colour::error()),
+                        ConnectionState::Closed => text("The display shut down.").color(Colour::warning()),
+                        ConnectionState::Retrying(attempt, delay) => text(format!("Connection lost. Retrying in {delay}s (attempt {attempt})...")).color(Colour::warning())
+                    })
+                )
+                .push(self.display_state.as_ref().map(|state| {
+                    let showing = match (&state.photo, &state.album) {
+                        (Some(photo), Some(album)) => format!("Showing {} from {}", photo.name, album.name),
+                        (None, Some(album)) => format!("Showing {}", album.name),
+                        _ => String::from("Nothing on screen")
+                    };
+                    let sync = match &state.sync {
+                        SyncState::Idle => String::new(),
+                        SyncState::Running => String::from(", adding album..."),
+                        SyncState::Finished(report) => format!(
+                            ", last sync found {} photos, {} new, {} changed and {} removed",
+                            report.photos, report.added, report.updated, report.removed
+                        ),
+                        SyncState::Failed(reason) => format!(", adding album failed: {reason}")
+                    };
+                    let authenticated = match state.authenticated {
+                        true => "",
+                        false => ", not signed in"
+                    };
+
+                    Row::new()
+                        .spacing(10)
+                        .push(text(format!("{showing} ({:?}){sync}{authenticated}", state.playback)))
+                        .push(match state.playback {
+                            PlaybackState::Playing => button("Pause").on_press(Message::OutgoingNetworkMessage(NetworkMessage::SetPlayback(PlaybackState::Paused))),
+                            PlaybackState::Paused => button("Resume").on_press(Message::OutgoingNetworkMessage(NetworkMessage::SetPlayback(PlaybackState::Playing))),
+                            PlaybackState::Stopped => button("Resume")
+                        })
+                }))
                 .push(
                     Scrollable::new(
                         Column::from_iter(self.albums
@@ -76,6 +255,8 @@ impl Application {
                             )
                         )
                     )
+                ).push(
+                    self.error.as_ref().map(|error| text(describe_error(error)).color(Colour::error()))
                 ).push(
                     Row::new()
                         .spacing(10)
@@ -97,33 +278,134 @@ impl Application {
 */
     }
 
+    fn entered_pin(&self) -> Option<String> {
+        match self.pin.trim() {
+            "" => None,
+            pin => Some(pin.to_string())
+        }
+    }
+
+    /// Replace any connection attempt still under way with a new one.
+    fn start_connection(&mut self, connecting: impl Future<Output = Res<(Client, Receiver<NetworkMessage>)>> + Send + 'static) -> Task<Message> {
+        self.error = None;
+
+        if let Some(handle) = self.connection_handle.take() {
+            handle.abort();
+        }
+
+        let (task, handle) = Task::abortable(Task::future(connecting)
+            .map(|res| match res {
+                Ok((client, receiver)) => Message::Connected(Arc::new(client), receiver),
+                Err(e) => Message::Error(e)
+            }));
+
+        self.connection_handle = Some(handle);
+
+        task
+    }
+
     pub fn update(&mut self, message: Message) -> Task<Message> {
 
         match message {
 
-            Message::Connect => {
+            Message::Discover => {
+                self.discovering = true;
+                Task::batch(vec![
+                    Task::future(Client::discover(self.group.clone()))
+                        .map(|res| match res {
+                            Ok(displays) => Message::Discovered(displays),
+                            Err(e) => Message::Error(e)
+                        }),
+                    Task::done(Message::LoadKnownDisplays)
+                ])
+            }
+
+            Message::LoadKnownDisplays => {
+                Task::future(interface::select_known_displays(self.database.derive()))
+                    .map(|res| match res {
+                        Ok(known_displays) => Message::KnownDisplays(known_displays),
+                        Err(e) => Message::Error(e)
+                    })
+            }
 
-                if let Some(handle) = self.connection_handle.take() {
-                    handle.abort();
+            Message::KnownDisplays(known_displays) => {
+                self.known_displays = known_displays;
+                Task::none()
+            }
+
+            Message::ForgetDisplay(display_id) => {
+                if self.selected_display.as_ref() == Some(&display_id) {
+                    self.selected_display = None;
                 }
 
-                let (task, handle) = Task::abortable(Task::future(Client::spawn())
+                Task::future(interface::delete_known_display(self.database.derive(), display_id))
                     .map(|res| match res {
-                        Ok((client, receiver)) => Message::Connected(Arc::new(client), receiver),
+                        Ok(()) => Message::LoadKnownDisplays,
                         Err(e) => Message::Error(e)
-                    }));
+                    })
+            }
+
+            Message::Discovered(displays) => {
+                self.discovering = false;
+
+                // With a single display on the network there is nothing to choose
+                if self.selected_display.is_none() && let [display] = displays.as_slice() {
+                    self.selected_display = Some(display.announcement.display_id.clone());
+                }
+
+                self.displays = displays;
+                Task::none()
+            }
 
-                self.connection_handle = Some(handle);
+            Message::SelectDisplay(display_id) => {
+                self.selected_display = Some(display_id);
+                Task::done(Message::Connect)
+            }
+
+            Message::Connect => {
+                let display_id = match self.selected_display.clone() {
+                    Some(display_id) => display_id,
+                    None => return Task::done(Message::Discover)
+                };
+
+                let connecting = Client::spawn(self.database.derive(), self.group.clone(), display_id, self.entered_pin(), Heartbeat::default());
+                self.start_connection(connecting)
+            }
+
+            Message::AddressInput(address) => {
+                self.address = address;
+                Task::none()
+            }
+
+            Message::ConnectAddress => {
+                let address = self.address.trim().to_string();
+                if address.is_empty() {
+                    return Task::none();
+                }
+
+                let connecting = Client::spawn_at(self.database.derive(), self.group.clone(), address, self.entered_pin(), Heartbeat::default());
+                self.start_connection(connecting)
+            }
 
-                task
+            Message::PinInput(pin) => {
+                self.pin = pin;
+                Task::none()
             }
 
             // Establish connection to the display server and initialise other asynchronous items.
             Message::Connected(client, receiver) => {
-                self.remote_connection = Some(client);
-                Task::stream(relay::Relay::consume_receiver(receiver, |nm|
-                    Some(Message::IncomingNetworkMessage(nm))
-                ))
+                self.remote_connection = Some(client.clone());
+                self.pin.clear();
+                self.address.clear();
+                Task::batch(vec![
+                    Task::done(Message::LoadKnownDisplays),
+                    Task::stream(relay::Relay::consume_receiver(receiver, |nm|
+                        Some(Message::IncomingNetworkMessage(nm))
+                    )),
+                    Task::done(Message::Request(NetworkMessage::RequestAllAlbums)),
+                    Task::done(Message::Request(NetworkMessage::RequestActiveAlbum)),
+                    Task::done(Message::Request(NetworkMessage::RequestState))
+                ])
             },
 
             Message::IncomingNetworkMessage(nm) => {
@@ -132,33 +414,66 @@ impl Application {
                     // IncomingNetworkMessage
 
                     NetworkMessage::NewAlbum(album) => {
-                        self.albums.push((album, vec![], false));
+                        if !self.albums.iter().any(|(existing, _, _)| existing.id == album.id) {
+                            self.albums.push((album, vec![], false));
+                        }
                         Task::none()
                     },
-                    
-                    NetworkMessage::ReturnAllAlbums(albums) => {
-                        self.albums.append(&mut albums.into_iter().map(|x| (x, vec![], false)).collect());
+
+                    // Replayed after every reconnect, so replace rather than append
+                    NetworkMessage::ReturnAllAlbums(_, albums) => {
+                        self.albums = albums.into_iter().map(|x| (x, vec![], false)).collect();
                         Task::none()
                     },
 
-                    NetworkMessage::ReturnPhotosInAlbum(album, mut photos) => {
-                        for album_mutref in &mut self.albums {
-                            if album_mutref.0.name == album.name {
-                                album_mutref.1.append(&mut photos)
-                            }
-                        }.into()
+                    NetworkMessage::ReturnPhotosInAlbum(_, album, photos) => {
+                        if let Some(entry) = self.albums.iter_mut().find(|(existing, _, _)| existing.id == album.id) {
+                            entry.1 = photos;
+                        }
+                        Task::none()
                     },
 
-                    NetworkMessage::Thumbnail(photo, bytes) => {
+                    NetworkMessage::Thumbnail(_, photo, bytes) => {
                         self.thumbnails.insert(photo.onedrive_id, Handle::from_bytes(bytes));
                         Task::none()
                     }
 
-                    NetworkMessage::ReturnActiveAlbum(album) => {
+                    NetworkMessage::ReturnActiveAlbum(_, album) => {
                         self.active_album = album;
                         Task::none()
                     },
 
+                    NetworkMessage::ReturnState(_, state) => {
+                        self.active_album = state.album.clone();
+                        self.display_state = Some(state);
+                        Task::none()
+                    },
+
+                    NetworkMessage::PermissionDenied(role) => {
+                        self.error = Some(ApplicationError::PermissionDenied(role).into());
+                        Task::none()
+                    },
+
+                    NetworkMessage::Event(event) => {
+                        if let DisplayEvent::AlbumChanged(album) = &event {
+                            self.active_album = album.clone();
+                        }
+                        if let Some(state) = self.display_state.as_mut() {
+                            state.apply(event);
+                        }
+                        Task::none()
+                    },
+
+                    // Events missed while disconnected are made up for with a fresh snapshot
+                    NetworkMessage::ConnectionState(state) => {
+                        let reconnected = matches!(state, ConnectionState::Connected) && self.connection_state.is_some();
+                        self.connection_state = Some(state);
+                        match reconnected {
+                            true => Task::done(Message::Request(NetworkMessage::RequestState)),
+                            false => Task::none()
+                        }
+                    },
+
                     _ => Task::none()
                 }
             },
@@ -185,6 +500,18 @@ impl Application {
                 Task::none()
             }
 
+            Message::Request(request) => {
+                if let Some(client) = self.remote_connection.clone() {
+                    Task::future(client.request(request, REQUEST_TIMEOUT))
+                        .map(|res| match res {
+                            Ok(reply) => Message::IncomingNetworkMessage(reply),
+                            Err(e) => Message::Error(e)
+                        })
+                } else {
+                    Task::done(Message::Error(ApplicationError::NotConnected.into()))
+                }
+            }
+
             Message::OutgoingNetworkMessage(nm) => {
                 // OutgoingNetworkMessage
                 if let Some(client) = self.remote_connection.as_ref() {
@@ -200,10 +527,36 @@ impl Application {
             }
 
             Message::Error(e) => {
-                eprintln!("Error: {e:?}");
+                self.discovering = false;
+                self.error = Some(e);
                 Task::none()
             }
         }
 
     }
 }
+
+/// Produce a message for the user, spelling out the failures they can act on.
+fn describe_error(error: &Error) -> String {
+    match error {
+        Error::ApplicationError(error) => match error.as_ref() {
+            ApplicationError::IncompatibleProtocol(local, remote) => format!(
+                "The display speaks protocol version {remote} but this controller speaks version {local}. Update both to the same release."
+            ),
+            ApplicationError::NotReflectionPeer => String::from("The remote device is not a reflection display."),
+            ApplicationError::HandshakeTimeout => String::from("The display did not respond to the handshake."),
+            ApplicationError::NoEndpoint => String::from("The chosen display did not answer and has no saved address. Make sure it is switched on and search again, or enter its address."),
+            ApplicationError::RequestTimedOut(_) => String::from("The display did not answer in time."),
+            ApplicationError::RequestFailed(_, reason) => format!("The display could not answer: {reason}"),
+            ApplicationError::PermissionDenied(role) => format!("Only a controller with the {} role may do that on this display.", role.name()),
+            other => format!("{other:?}")
+        },
+        Error::StdIoError(error) => format!("Could not reach the display: {error}"),
+        Error::SecureChannelError(error) => match error.as_ref() {
+            SecureChannelError::PairingRequired => String::from("This controller is not paired with the display yet. Enter the PIN shown on the display."),
+            SecureChannelError::PairingRejected => String::from("The display rejected the pairing. Check the PIN, a new one is shown after every attempt. A guest PIN works once, until it expires."),
+            other => format!("{other:?}")
+        },
+        other => format!("{other:?}")
+    }
+}
@@ -1,16 +1,30 @@
 use std::sync::Arc;
 use async_channel::Receiver;
 
-use crate::communication::NetworkMessage;
+use crate::communication::{NetworkMessage, RequestId};
 use crate::communication::client::Client;
+use crate::communication::discovery::{DiscoveredDisplay, KnownDisplay};
 use crate::error::Error;
 
 #[derive(Clone, Debug)]
 pub enum Message {
     None,
 
+    // Find the displays on the LAN and choose one
+    Discover,
+    Discovered(Vec<DiscoveredDisplay>),
+    SelectDisplay(String),
+    // Displays reached before, for when discovery finds nothing
+    LoadKnownDisplays,
+    KnownDisplays(Vec<KnownDisplay>),
+    ForgetDisplay(String),
+
     // Attempt to form a connection with the display server
     Connect,
+    PinInput(String),
+    // Connect by host name or IP address rather than through discovery
+    AddressInput(String),
+    ConnectAddress,
     Connected(Arc<Client>, Receiver<NetworkMessage>),
 
     // Perform an OAUTH2 authentication, and relay to display server
@@ -19,6 +33,8 @@ pub enum Message {
     // Process messages to and from the display server
     IncomingNetworkMessage(NetworkMessage),
     OutgoingNetworkMessage(NetworkMessage),
+    // Send a request under a fresh id, and process the reply as an incoming message
+    Request(fn(RequestId) -> NetworkMessage),
 
     // GUI
     Hover(usize),
@@ -1,15 +1,33 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use async_channel::Receiver;
-use iced::Task;
+use futures_util::StreamExt;
+use iced::{Subscription, Task};
 use iced::widget::{Container, text};
 use iced::widget::Column;
-use rusqlite_async::database::Database;
+use rusqlite_async::database::{DataLink, Database};
 
-use crate::authentication::oauth2::wrapper::first_authentication;
-use crate::communication::NetworkMessage;
-use crate::communication::server::Server;
+use crate::authentication::oauth2::wrapper::{first_authentication, refresh_authentication};
+use crate::communication::{ConnectionState, DisplayEvent, DisplayState, NetworkMessage, PlaybackState, RequestId, SyncState, transfer};
+use crate::communication::discovery::DisplayStatus;
+use crate::communication::outbox::Outbox;
+use crate::communication::server::{PeerId, Recipient, Server, ServerConfig};
+use crate::error::{Error, Res};
+use crate::frontend::application::ApplicationError;
 use crate::frontend::colour::Colour;
 use crate::frontend::display_application::message::Message;
-use crate::{authentication::oauth2::api::TokenSet, database::interface, directories::create::Directories, onedrive::get_drive::DriveData};
+use crate::onedrive::api::AccessToken;
+use crate::onedrive::download::{download_drive_item, get_existant_path, get_existant_thumbnail};
+use crate::onedrive::get_album_children::{Album, Photo, SyncReport, check_all_albums, new_album};
+use crate::{authentication::oauth2::api::TokenSet, database::{interface, migrations}, directories::create::Directories, onedrive::get_drive::DriveData};
+
+/// How long each photo of the album stays on screen.
+const SLIDE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long before the access token expires it is refreshed.
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
 
 pub struct Application {
     connection: Server,
@@ -19,21 +37,42 @@ pub struct Application {
     // Authentication
     tokenset: Option<TokenSet>,
     drivedata: Option<DriveData>,
+    refreshing: bool,
+
+    // The album currently selected for display by a controller, and the photo of it on screen
+    active_album: Option<Album>,
+    photos: Vec<Photo>,
+    photo_index: usize,
+    playback: PlaybackState,
+    sync: SyncState,
+
+    // Photo transfers in progress, so they can be cancelled
+    transfers: HashMap<(PeerId, RequestId), iced::task::Handle>
 }
 
 impl Application {
-    pub fn new() -> (Self, Receiver<rusqlite_async::error::Error>, Receiver<NetworkMessage>) {
+    pub fn new(config: ServerConfig) -> (Self, Receiver<rusqlite_async::error::Error>, Receiver<(PeerId, NetworkMessage)>) {
         let directories = Directories::create_or_load().expect("[CRITICAL ERROR] Unable to find suitable directories location.");
         let (database, error_receiver) = Database::new(directories.root.clone());
-        interface::create_tables(database.derive()).expect("[CRITICAL ERROR] Unable to create database tables.");
-        let (server, network_receiver) = Server::spawn();
+        if let Err(error) = migrations::migrate_blocking(database.derive()) {
+            eprintln!("[CRITICAL ERROR] Unable to migrate the database: {error:?}");
+            std::process::exit(1);
+        }
+        let (server, network_receiver) = Server::spawn(database.derive(), config);
 
         (Self {
             connection: server,
             database,
             directories,
             tokenset: None,
-            drivedata: None
+            drivedata: None,
+            refreshing: false,
+            active_album: None,
+            photos: Vec::new(),
+            photo_index: 0,
+            playback: PlaybackState::Stopped,
+            sync: SyncState::Idle,
+            transfers: HashMap::new()
         }, error_receiver, network_receiver)
     }
 
@@ -41,20 +80,63 @@ impl Application {
         Container::new(
             Column::new()
                 .push(text("Placeholder..."))
+                .push(text(format!("Pairing PIN: {}", self.connection.get_pin())))
+                .push(self.connection.get_api_token().map(|token| text(format!("API token: {token}"))))
                 .push(
                     if self.tokenset.is_none() { Some(text("Not authenticated...").color(Colour::error())) }
                     else { None }
                 )
+                .extend(
+                    self.connection.get_active_connections().into_iter().map(|addr| text(format!("Connected to {addr}!")).into())
+                )
+                .push(
+                    self.active_album.as_ref().map(|album| text(format!("Playing {}", album.name)))
+                )
                 .push(
-                    self.connection.get_active_connection().map(|ip| text(format!("Connected to {ip}!")))
+                    self.photos.get(self.photo_index).map(|photo| text(format!("Showing {} ({:?})", photo.name, self.playback)))
                 )
         )
     }
 
+    /// Advance the slideshow and refresh the token before it expires, and shut down cleanly when the window is closed or on Ctrl-C.
+    pub fn subscription(&self) -> Subscription<Message> {
+        Subscription::batch(vec![
+            iced::time::every(SLIDE_INTERVAL).map(|_| Message::Tick),
+            iced::window::close_requests().map(|_| Message::Shutdown),
+            Subscription::run(|| futures_util::stream::once(tokio::signal::ctrl_c()).map(|_| Message::Shutdown))
+        ])
+    }
+
+    fn state(&self) -> DisplayState {
+        DisplayState {
+            album: self.active_album.clone(),
+            photo: self.photos.get(self.photo_index).cloned(),
+            playback: self.playback,
+            sync: self.sync.clone(),
+            authenticated: self.tokenset.is_some()
+        }
+    }
+
+    /// Tell every controller about changes to the state, and refresh the status answered to discovery.
+    fn broadcast(&self, events: Vec<DisplayEvent>) -> Task<Message> {
+        self.publish_status();
+        Task::batch(events.into_iter().map(|event| Task::done(Message::OutgoingNetworkMessage(Recipient::All, NetworkMessage::Event(event)))))
+    }
+
+    /// Advertise the current state to controllers browsing for displays.
+    fn publish_status(&self) {
+        self.connection.set_status(DisplayStatus {
+            authenticated: self.tokenset.is_some(),
+            active_album: self.active_album.as_ref().map(|album| album.name.clone()),
+            // Filled in by the server from its own connection list
+            controllers: 0
+        });
+    }
+
     pub fn update(&mut self, message: Message) -> Task<Message> {
         match message {
             Message::None => Task::none(),
-            Message::IncomingNetworkMessage(nm) => match nm {
+            Message::IncomingNetworkMessage(peer, nm) => match nm {
                 NetworkMessage::TokenSet(tokenset) => {
                     let datalink = self.database.derive();
                     Task::future(first_authentication(datalink, tokenset))
@@ -66,28 +148,346 @@ impl Application {
                         })
                 },
 
-                _ => Task::none()
-            }
+                NetworkMessage::Sharelink(id, sharelink) => {
+                    match (self.tokenset.as_ref(), self.drivedata.as_ref()) {
+                        (Some(tokenset), Some(drivedata)) => {
+                            let access_token = AccessToken::new(tokenset.access_token.clone());
+                            let drive_id = drivedata.id.clone();
+                            let datalink = self.database.derive();
+                            let album_root_dir = self.directories.albums.clone();
+                            self.sync = SyncState::Running;
 
-            Message::OutgoingNetworkMessage(nm) => {
-                if self.connection.get_active_connection().is_some() {
-                    let sender = self.connection.get_sender();
-                    Task::future(Server::send_network_message(sender, nm))
+                            Task::batch(vec![
+                                self.broadcast(vec![DisplayEvent::SyncChanged(SyncState::Running)]),
+                                Task::future(new_album(access_token, drive_id, sharelink, datalink, album_root_dir))
+                                    .map(move |res| match res {
+                                        Ok((album, _, report)) => Message::AlbumSynced(peer, id, album, report),
+                                        Err(e) => Message::SyncFailed(Some((peer, id)), e)
+                                    })
+                            ])
+                        }
+
+                        _ => Task::batch(vec![
+                            Task::done(Message::OutgoingNetworkMessage(Recipient::Peer(peer), NetworkMessage::RequestFailed(id, format!("{:?}", ApplicationError::NotAuthenticated)))),
+                            Task::done(Message::Error(Error::from(ApplicationError::NotAuthenticated)))
+                        ])
+                    }
+                },
+
+                NetworkMessage::PlayAlbum(album) => {
+                    self.active_album = Some(album.clone());
+                    self.photos.clear();
+                    self.photo_index = 0;
+                    self.playback = PlaybackState::Playing;
+
+                    Task::batch(vec![
+                        self.broadcast(vec![
+                            DisplayEvent::AlbumChanged(Some(album.clone())),
+                            DisplayEvent::PhotoChanged(None),
+                            DisplayEvent::PlaybackChanged(PlaybackState::Playing)
+                        ]),
+                        Task::future(interface::select_photos_in_album(self.database.derive(), album.id))
+                            .map(|res| match res {
+                                Ok((album, photos)) => Message::PhotosLoaded(album, photos),
+                                Err(e) => Message::Error(e)
+                            })
+                    ])
+                },
+
+                NetworkMessage::SetPlayback(playback) if playback != self.playback => {
+                    self.playback = playback;
+                    let mut events = vec![DisplayEvent::PlaybackChanged(playback)];
+
+                    // Stopping puts the album away, pausing keeps the current photo on screen
+                    if playback == PlaybackState::Stopped {
+                        self.active_album = None;
+                        self.photos.clear();
+                        self.photo_index = 0;
+                        events.push(DisplayEvent::AlbumChanged(None));
+                        events.push(DisplayEvent::PhotoChanged(None));
+                    }
+
+                    self.broadcast(events)
+                },
+
+                NetworkMessage::RequestState(id) => {
+                    Task::done(Message::OutgoingNetworkMessage(Recipient::Peer(peer), NetworkMessage::ReturnState(id, self.state())))
+                },
+
+                NetworkMessage::RequestAllAlbums(id) => {
+                    Task::future(interface::select_albums(self.database.derive()))
+                        .map(move |res| Message::OutgoingNetworkMessage(Recipient::Peer(peer), match res {
+                            Ok(albums) => NetworkMessage::ReturnAllAlbums(id, albums),
+                            Err(e) => NetworkMessage::RequestFailed(id, format!("{e:?}"))
+                        }))
+                },
+
+                NetworkMessage::RequestPhotosInAlbum(id, album) => {
+                    Task::future(interface::select_photos_in_album(self.database.derive(), album.id))
+                        .map(move |res| Message::OutgoingNetworkMessage(Recipient::Peer(peer), match res {
+                            Ok((album, photos)) => NetworkMessage::ReturnPhotosInAlbum(id, album, photos),
+                            Err(e) => NetworkMessage::RequestFailed(id, format!("{e:?}"))
+                        }))
+                },
+
+                NetworkMessage::RequestThumbnails(id) => {
+                    let datalink = self.database.derive();
+                    let album_root_dir = self.directories.albums.clone();
+                    let access_token = self.tokenset.as_ref().map(|tokenset| AccessToken::new(tokenset.access_token.clone()));
+
+                    Task::future(send_thumbnails(self.connection.get_sender(), peer, id, datalink, album_root_dir, access_token))
                         .map(|res| match res {
                             Ok(()) => Message::None,
                             Err(e) => Message::Error(e)
                         })
-                } else {
+                },
+
+                NetworkMessage::RequestThumbnail(id, album, photo) => {
+                    let access_token = self.tokenset.as_ref().map(|tokenset| AccessToken::new(tokenset.access_token.clone()));
+
+                    Task::future(read_thumbnail(photo.clone(), album.onedrive_id, self.directories.albums.clone(), access_token))
+                        .map(move |res| Message::OutgoingNetworkMessage(Recipient::Peer(peer), match res {
+                            Ok(Some(bytes)) => NetworkMessage::Thumbnail(id, photo.clone(), bytes),
+                            Ok(None) => NetworkMessage::RequestFailed(id, String::from("No thumbnail is available for this photo")),
+                            Err(e) => NetworkMessage::RequestFailed(id, format!("{e:?}"))
+                        }))
+                },
+
+                NetworkMessage::RequestPhoto(id, album, photo) => {
+                    let access_token = self.tokenset.as_ref().map(|tokenset| AccessToken::new(tokenset.access_token.clone()));
+
+                    let sender = self.connection.get_sender();
+
+                    // Each chunk is read only once the previous one has been queued, so a slow controller slows the reading down
+                    let (task, handle) = Task::abortable(
+                        Task::future(original_path(photo, album.onedrive_id, self.directories.albums.clone(), access_token))
+                            .then(move |res| match res {
+                                Ok(path) => {
+                                    let chunks = transfer::stream_file(id, path).map(move |res| match res {
+                                        Ok(nm) => nm,
+                                        Err(e) => NetworkMessage::RequestFailed(id, format!("{e:?}"))
+                                    });
+
+                                    Task::future(Server::send_network_messages(sender.clone(), Recipient::Peer(peer), chunks))
+                                        .map(|res| match res {
+                                            Ok(()) => Message::None,
+                                            Err(e) => Message::Error(e)
+                                        })
+                                },
+                                Err(e) => Task::done(Message::OutgoingNetworkMessage(Recipient::Peer(peer), NetworkMessage::RequestFailed(id, format!("{e:?}"))))
+                            })
+                            .chain(Task::done(Message::TransferFinished(peer, id)))
+                    );
+
+                    self.transfers.insert((peer, id), handle);
+                    task
+                },
+
+                NetworkMessage::CancelTransfer(id) => match self.transfers.remove(&(peer, id)) {
+                    Some(handle) => {
+                        handle.abort();
+                        Task::done(Message::OutgoingNetworkMessage(Recipient::Peer(peer), NetworkMessage::RequestFailed(id, String::from("Transfer cancelled"))))
+                    },
+                    None => Task::none()
+                },
+
+                NetworkMessage::ApiUnavailable(reason) => Task::done(Message::Error(ApplicationError::ApiUnavailable(reason).into())),
+
+                // Reported by the server itself, processing it is enough to refresh the list of controllers
+                NetworkMessage::ConnectionState(state) => {
+                    // Nobody is left to receive the transfers of a controller that went away
+                    if matches!(state, ConnectionState::Lost | ConnectionState::TimedOut) {
+                        self.transfers.retain(|(transfer_peer, _), handle| {
+                            if *transfer_peer == peer { handle.abort(); }
+                            *transfer_peer != peer
+                        });
+                    }
+
                     Task::none()
+                },
+
+                NetworkMessage::RequestActiveAlbum(id) => {
+                    Task::done(Message::OutgoingNetworkMessage(Recipient::Peer(peer), NetworkMessage::ReturnActiveAlbum(Some(id), self.active_album.clone())))
+                },
+
+                // Only owners get this far, the server refuses these to anyone else
+                NetworkMessage::RequestControllers(id) => {
+                    Task::future(self.connection.controllers())
+                        .map(move |res| Message::OutgoingNetworkMessage(Recipient::Peer(peer), match res {
+                            Ok(controllers) => NetworkMessage::ReturnControllers(id, controllers),
+                            Err(e) => NetworkMessage::RequestFailed(id, format!("{e:?}"))
+                        }))
+                },
+
+                NetworkMessage::SetRole(id, controller_id, role) => {
+                    let set_role = self.connection.set_role(controller_id, role);
+                    let controllers = self.connection.controllers();
+
+                    Task::future(async move {
+                        set_role.await?;
+                        controllers.await
+                    })
+                    .map(move |res| Message::OutgoingNetworkMessage(Recipient::Peer(peer), match res {
+                        Ok(controllers) => NetworkMessage::ReturnControllers(id, controllers),
+                        Err(e) => NetworkMessage::RequestFailed(id, format!("{e:?}"))
+                    }))
+                },
+
+                NetworkMessage::RequestGuestPin(id, minutes) => {
+                    Task::done(Message::OutgoingNetworkMessage(Recipient::Peer(peer), match self.connection.issue_guest_pin(minutes) {
+                        Ok((pin, expires_at)) => NetworkMessage::ReturnGuestPin(id, pin, expires_at),
+                        Err(e) => NetworkMessage::RequestFailed(id, format!("{e:?}"))
+                    }))
+                },
+
+                _ => Task::none()
+            }
+
+            // The server drops messages for peers that are not connected, and the HTTP API is never in the list of controllers
+            Message::OutgoingNetworkMessage(recipient, nm) => {
+                let sender = self.connection.get_sender();
+                Task::future(Server::send_network_message(sender, recipient, nm))
+                    .map(|res| match res {
+                        Ok(()) => Message::None,
+                        Err(e) => Message::Error(e)
+                    })
+            }
+
+            Message::PhotosLoaded(album, photos) => {
+                // A different album may have been chosen while these were loading
+                if self.active_album.as_ref().map(|active| active.id) != Some(album.id) {
+                    return Task::none();
                 }
+
+                self.photos = photos;
+                self.photo_index = 0;
+                self.broadcast(vec![DisplayEvent::PhotoChanged(self.photos.first().cloned())])
+            }
+
+            Message::Tick => {
+                let mut tasks = Vec::new();
+
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs() as usize).unwrap_or(0);
+                let expiring = self.tokenset.as_ref().filter(|tokenset| tokenset.absolute_expiration <= now + REFRESH_MARGIN.as_secs() as usize);
+                if let Some(tokenset) = expiring.filter(|_| !self.refreshing) {
+                    self.refreshing = true;
+                    tasks.push(Task::future(refresh_authentication(self.database.derive(), tokenset.clone()))
+                        .map(|res| match res {
+                            Ok(tokenset) => Message::TokenRefreshed(tokenset),
+                            Err(e) => Message::RefreshFailed(e)
+                        }));
+                }
+
+                if self.playback == PlaybackState::Playing && !self.photos.is_empty() {
+                    self.photo_index = (self.photo_index + 1) % self.photos.len();
+                    tasks.push(self.broadcast(vec![DisplayEvent::PhotoChanged(self.photos.get(self.photo_index).cloned())]));
+                }
+
+                Task::batch(tasks)
+            }
+
+            Message::TokenRefreshed(tokenset) => {
+                self.refreshing = false;
+                self.tokenset = Some(tokenset);
+                Task::none()
+            }
+
+            // Tried again on the next tick while the access token lasts, the display is only signed out once it has expired
+            Message::RefreshFailed(e) => {
+                self.refreshing = false;
+
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs() as usize).unwrap_or(0);
+                if self.tokenset.as_ref().is_none_or(|tokenset| tokenset.absolute_expiration > now) {
+                    return Task::done(Message::Error(e));
+                }
+
+                self.tokenset = None;
+                Task::batch(vec![
+                    self.broadcast(vec![DisplayEvent::AuthenticationChanged(false)]),
+                    Task::done(Message::Error(e))
+                ])
+            }
+
+            Message::AlbumSynced(peer, id, album, report) => {
+                self.sync = SyncState::Finished(report);
+                Task::batch(vec![
+                    Task::done(Message::OutgoingNetworkMessage(Recipient::Peer(peer), NetworkMessage::ReturnNewAlbum(id, album.clone()))),
+                    Task::done(Message::OutgoingNetworkMessage(Recipient::All, NetworkMessage::NewAlbum(album))),
+                    self.broadcast(vec![DisplayEvent::SyncChanged(self.sync.clone())])
+                ])
+            }
+
+            Message::SyncFailed(request, e) => {
+                self.sync = SyncState::Failed(format!("{e:?}"));
+                Task::batch(vec![
+                    self.broadcast(vec![DisplayEvent::SyncChanged(self.sync.clone())]),
+                    match request {
+                        Some((peer, id)) => Task::done(Message::OutgoingNetworkMessage(Recipient::Peer(peer), NetworkMessage::RequestFailed(id, format!("{e:?}")))),
+                        None => Task::none()
+                    },
+                    Task::done(Message::Error(e))
+                ])
+            }
+
+            Message::TransferFinished(peer, id) => {
+                self.transfers.remove(&(peer, id));
+                Task::none()
             }
 
             Message::AuthenticationComplete(tokenset, drivedata) => {
+                let access_token = AccessToken::new(tokenset.access_token.clone());
+                let drive_id = drivedata.id.clone();
+                let datalink = self.database.derive();
+                let album_root_dir = self.directories.albums.clone();
+
                 self.tokenset = Some(tokenset);
                 self.drivedata = Some(drivedata);
-                Task::none()
+                self.sync = SyncState::Running;
+
+                Task::batch(vec![
+                    self.broadcast(vec![DisplayEvent::AuthenticationChanged(true), DisplayEvent::SyncChanged(SyncState::Running)]),
+                    Task::future(check_all_albums(access_token, drive_id, datalink, album_root_dir))
+                        .map(|res| match res {
+                            Ok((synced, removed)) => Message::LibrarySynced(
+                                synced.into_iter().fold(SyncReport::default(), |total, (_, _, report)| total.merge(report)),
+                                removed
+                            ),
+                            Err(e) => Message::SyncFailed(None, e)
+                        })
+                ])
+            }
+
+            Message::LibrarySynced(report, removed) => {
+                self.sync = SyncState::Finished(report);
+                let mut events = vec![DisplayEvent::SyncChanged(self.sync.clone())];
+
+                if self.active_album.as_ref().is_some_and(|active| removed.iter().any(|album| album.id == active.id)) {
+                    self.active_album = None;
+                    self.photos.clear();
+                    self.photo_index = 0;
+                    events.push(DisplayEvent::AlbumChanged(None));
+                    events.push(DisplayEvent::PhotoChanged(None));
+                }
+
+                self.broadcast(events)
+            }
+
+            Message::Shutdown => {
+                let shutdown = self.connection.shutdown();
+                let database = self.database.derive();
+
+                Task::future(async move {
+                    if let Err(e) = shutdown.await {
+                        eprintln!("Server did not shut down cleanly: {e:?}");
+                    }
+                    if let Err(e) = interface::flush(database).await {
+                        eprintln!("Unable to flush the database: {e:?}");
+                    }
+                }).map(|_| Message::ShutdownComplete)
             }
 
+            Message::ShutdownComplete => iced::exit(),
+
             Message::Error(e) => {
                 println!("Error: {e:?}");
                 Task::none()
@@ -95,3 +495,62 @@ impl Application {
         }
     }
 }
+
+/// Path of the original photo, downloading it first if it is not cached and the display is authenticated.
+async fn original_path(photo: Photo, album_id: String, album_root_dir: PathBuf, access_token: Option<AccessToken>) -> Res<PathBuf> {
+    if let Some(path) = get_existant_path(photo.clone(), album_id.clone(), album_root_dir.clone()).await {
+        return Ok(path);
+    }
+
+    match access_token {
+        Some(access_token) => Ok(download_drive_item(access_token, photo, album_root_dir, album_id).await?.0),
+        None => Err(ApplicationError::NotAuthenticated.into())
+    }
+}
+
+/// Send the thumbnail of every photo in every album, one at a time so a slow controller is not sent them faster than it reads.
+/// A photo whose thumbnail cannot be read is skipped. Ends with ThumbnailsEnd, queued behind the thumbnails as it is bulk too.
+async fn send_thumbnails(sender: Outbox<(Recipient, NetworkMessage)>, peer: PeerId, id: RequestId, database: DataLink, album_root_dir: PathBuf, access_token: Option<AccessToken>) -> Res<()> {
+    let albums = match interface::select_albums(database.clone()).await {
+        Ok(albums) => albums,
+        Err(e) => return Server::send_network_message(sender, Recipient::Peer(peer), NetworkMessage::RequestFailed(id, format!("{e:?}"))).await
+    };
+
+    for album in albums {
+        let (album, photos) = match interface::select_photos_in_album(database.clone(), album.id).await {
+            Ok(found) => found,
+            Err(e) => {
+                println!("Error: {e:?}");
+                continue;
+            }
+        };
+
+        for photo in photos {
+            match read_thumbnail(photo.clone(), album.onedrive_id.clone(), album_root_dir.clone(), access_token.clone()).await {
+                Ok(Some(bytes)) => Server::send_network_message(sender.clone(), Recipient::Peer(peer), NetworkMessage::Thumbnail(id, photo, bytes)).await?,
+                Ok(None) => {},
+                Err(e) => println!("Error: {e:?}")
+            }
+        }
+    }
+
+    Server::send_network_message(sender, Recipient::Peer(peer), NetworkMessage::ThumbnailsEnd(id)).await?;
+
+    Ok(())
+}
+
+/// Read the cached thumbnail of a photo, downloading the photo first if it is missing and the display is authenticated.
+async fn read_thumbnail(photo: Photo, album_id: String, album_root_dir: PathBuf, access_token: Option<AccessToken>) -> Res<Option<Vec<u8>>> {
+    let thumbnail_path = match get_existant_thumbnail(photo.clone(), album_id.clone(), album_root_dir.clone()).await {
+        Some(path) => Some(path),
+        None => match access_token {
+            Some(access_token) => download_drive_item(access_token, photo, album_root_dir, album_id).await?.1,
+            None => None
+        }
+    };
+
+    match thumbnail_path {
+        Some(path) => Ok(Some(tokio::fs::read(path).await?)),
+        None => Ok(None)
+    }
+}
@@ -1,6 +1,8 @@
 use crate::authentication::oauth2::api::TokenSet;
-use crate::communication::NetworkMessage;
+use crate::communication::{NetworkMessage, RequestId};
+use crate::communication::server::{PeerId, Recipient};
 use crate::error::Error;
+use crate::onedrive::get_album_children::{Album, Photo, SyncReport};
 use crate::onedrive::get_drive::DriveData;
 
 #[derive(Clone, Debug)]
@@ -8,11 +10,30 @@ pub enum Message {
     None,
 
     // Process messages to and from the control application
-    IncomingNetworkMessage(NetworkMessage),
-    OutgoingNetworkMessage(NetworkMessage),
+    IncomingNetworkMessage(PeerId, NetworkMessage),
+    OutgoingNetworkMessage(Recipient, NetworkMessage),
+    // The last chunk of a photo transfer has been queued
+    TransferFinished(PeerId, RequestId),
+
+    // Slideshow and album synchronisation
+    PhotosLoaded(Album, Vec<Photo>),
+    Tick,
+    // An album added from a share link, with the controller and request that asked for it
+    AlbumSynced(PeerId, RequestId, Album, SyncReport),
+    // Every album re-synced after authenticating, with the albums removed for no longer existing
+    LibrarySynced(SyncReport, Vec<Album>),
+    // The request to answer when the sync was for a share link
+    SyncFailed(Option<(PeerId, RequestId)>, Error),
 
     // Save incoming authentication information
     AuthenticationComplete(TokenSet, DriveData),
+    // The access token was renewed ahead of its expiry, or could not be
+    TokenRefreshed(TokenSet),
+    RefreshFailed(Error),
+
+    // Say goodbye to controllers and flush the database, then exit
+    Shutdown,
+    ShutdownComplete,
 
     Error(Error)
 }
@@ -5,4 +5,5 @@ pub mod widgets;
 
 pub mod display_application;
 pub mod control_application;
+pub mod command_line;
 pub mod colour;
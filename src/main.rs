@@ -2,6 +2,9 @@
 #![allow(clippy::enum_variant_names)]
 
 use iced::Task;
+use crate::communication::codec::Encoding;
+use crate::communication::discovery::Group;
+use crate::communication::server::ServerConfig;
 use crate::util::relay::Relay;
 
 use std::env::args;
@@ -15,39 +18,125 @@ mod onedrive;
 mod frontend;
 mod communication;
 
+/// Read `--group <name>` and `--port <port>`, the options shared by displays and controllers.
+/// Returns whether the argument was one of them.
+fn group_argument<'a>(group: &mut Group, argument: &str, arguments: &mut impl Iterator<Item = &'a String>) -> bool {
+    match argument {
+        "--group" => match arguments.next() {
+            Some(identifier) => group.identifier = identifier.clone(),
+            None => eprintln!("--group expects a name")
+        },
+        "--port" => match arguments.next().map(|port| port.parse()) {
+            Some(Ok(port)) => group.port = port,
+            _ => eprintln!("--port expects a port number such as 7878")
+        },
+        _ => return false
+    }
+
+    true
+}
+
+/// Read `--name <name>`, `--id <id>`, `--api <address>`, `--bind <address>`, `--codec <encoding>`, `--max-frame <bytes>`
+/// and the group options following the display subcommand.
+fn display_config(arguments: &[String]) -> ServerConfig {
+    let mut config = ServerConfig::default();
+    let mut arguments = arguments.iter();
+
+    while let Some(argument) = arguments.next() {
+        if group_argument(&mut config.group, argument, &mut arguments) {
+            continue;
+        }
+
+        match argument.as_str() {
+            "--name" => config.name = arguments.next().cloned(),
+            "--id" => config.display_id = arguments.next().cloned(),
+            "--api" => match arguments.next().map(|address| address.parse()) {
+                Some(Ok(address)) => config.api = Some(address),
+                _ => eprintln!("--api expects an address such as 0.0.0.0:8080")
+            },
+            "--bind" => match arguments.next().map(|address| address.parse()) {
+                Some(Ok(address)) => config.bind = Some(address),
+                _ => eprintln!("--bind expects an address such as 0.0.0.0 or ::")
+            },
+            "--codec" => match arguments.next().map(|name| Encoding::parse(name)) {
+                Some(Some(encoding)) => config.codec = Some(encoding),
+                _ => eprintln!("--codec expects rkyv or json")
+            },
+            "--max-frame" => match arguments.next().map(|bytes| bytes.parse()) {
+                Some(Ok(bytes)) => config.max_frame = Some(bytes),
+                _ => eprintln!("--max-frame expects a size in bytes such as 16777216")
+            },
+            other => eprintln!("Ignoring unknown argument {other}")
+        }
+    }
+
+    config
+}
+
+/// Read the group options following the control subcommand.
+fn control_config(arguments: &[String]) -> Group {
+    let mut group = Group::default();
+    let mut arguments = arguments.iter();
+
+    while let Some(argument) = arguments.next() {
+        if !group_argument(&mut group, argument, &mut arguments) {
+            eprintln!("Ignoring unknown argument {argument}");
+        }
+    }
+
+    group
+}
+
 fn main() -> iced::Result {
 
-    let args = args().nth(1).unwrap().to_string();
+    let arguments: Vec<String> = args().skip(1).collect();
+    let args = arguments.first().unwrap().to_string();
 
     match args.as_str() {
         "display" => {
-            iced::application(||
+            let config = display_config(&arguments[1..]);
+            iced::application(move ||
                 {
-                    let (application, error_handle, network_receiver) = crate::frontend::display_application::application::Application::new();
+                    let (application, error_handle, network_receiver) = crate::frontend::display_application::application::Application::new(config.clone());
                     (
                         application,
                         Task::batch(vec![
                             Task::stream(Relay::consume_receiver(error_handle, |e| Some(crate::frontend::display_application::message::Message::Error(e.into())))),
-                            Task::stream(Relay::consume_receiver(network_receiver, |nm| Some(crate::frontend::display_application::message::Message::IncomingNetworkMessage(nm))))
+                            Task::stream(Relay::consume_receiver(network_receiver, |(peer, nm)| Some(crate::frontend::display_application::message::Message::IncomingNetworkMessage(peer, nm))))
                         ])
                     )
                 },
                 crate::frontend::display_application::application::Application::update,
                 crate::frontend::display_application::application::Application::view,
-            ).title("Display").run()
+            )
+            .subscription(crate::frontend::display_application::application::Application::subscription)
+            // The display says goodbye to its controllers before the window goes away
+            .exit_on_close_request(false)
+            .title("Display")
+            .run()
         },
 
         "control" => {
-            iced::application(||
-                (
-                    crate::frontend::control_application::application::Application::default(),
-                    Task::none()
-                ),
+            let group = control_config(&arguments[1..]);
+            iced::application(move ||
+                {
+                    let (application, error_handle) = crate::frontend::control_application::application::Application::new(group.clone());
+                    (
+                        application,
+                        Task::batch(vec![
+                            Task::stream(Relay::consume_receiver(error_handle, |e| Some(crate::frontend::control_application::message::Message::Error(e.into())))),
+                            Task::done(crate::frontend::control_application::message::Message::Discover)
+                        ])
+                    )
+                },
                 crate::frontend::control_application::application::Application::update,
                 crate::frontend::control_application::application::Application::view,
             ).title("Control").run()
         }
 
+        // Headless controller for scripts, prints JSON and exits
+        "ctl" => std::process::exit(crate::frontend::command_line::run(&arguments[1..])),
+
         _ => Ok(())
     }
 }
@@ -1,4 +1,4 @@
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
 use serde::de::DeserializeOwned;
 
 use crate::error::Res;
@@ -7,7 +7,9 @@ const URLBASE: &str = "https://graph.microsoft.com/v1.0";
 
 #[derive(Clone, Debug)]
 pub enum OnedriveError {
-    BadStatus
+    BadStatus,
+    // The item asked for does not exist, or is no longer shared
+    NotFound
 }
 
 #[derive(Clone, Debug)]
@@ -33,6 +35,11 @@ pub async fn make_request<T: DeserializeOwned>(url: &str, access_token: String,
         .send()
         .await?;
 
+    // Told apart from other failures, as sync removes what is no longer there
+    if res.status() == StatusCode::NOT_FOUND {
+        return Err(OnedriveError::NotFound.into());
+    }
+
     // If the status indicates failure, don't bother with serde.
     if !res.status().is_success() {
         println!("{}", res.text().await?);
@@ -133,3 +133,26 @@ pub async fn download_drive_item(
 
     Ok((file_path, thumbnail_path))
 }
+
+/// Delete the cached original and thumbnail of a photo in an album, whichever of them exist.
+pub async fn remove_cached(photo: Photo, album_id: String, album_root_dir: PathBuf) -> Res<()> {
+    if let Some(path) = get_existant_thumbnail(photo.clone(), album_id.clone(), album_root_dir.clone()).await {
+        tokio::fs::remove_file(path).await?;
+    }
+
+    if let Some(path) = get_existant_path(photo, album_id, album_root_dir).await {
+        tokio::fs::remove_file(path).await?;
+    }
+
+    Ok(())
+}
+
+/// Delete every cached file of an album.
+pub async fn remove_album_directory(album_id: String, album_root_dir: PathBuf) -> Res<()> {
+    let directory = album_root_dir.join(&album_id);
+    if directory.exists() {
+        tokio::fs::remove_dir_all(&directory).await?;
+    }
+
+    Ok(())
+}
@@ -1,3 +1,5 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
 use std::time::{SystemTime, UNIX_EPOCH};
 use async_channel::unbounded;
 use chrono::{DateTime, Utc};
@@ -7,9 +9,10 @@ use rusqlite_async::database::DataLink;
 use serde::{Deserialize, Serialize};
 use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
 
-use crate::database::interface::{insert_album, insert_entry, insert_photo, select_albums};
-use crate::onedrive::api::{AccessToken, make_request};
-use crate::error::Res;
+use crate::database::interface::{count_entries_for_photo, delete_album, delete_entry, delete_photo, insert_entry, select_albums, select_albums_with_photo, select_photos_in_album, upsert_album, upsert_photo};
+use crate::onedrive::api::{AccessToken, OnedriveError, make_request};
+use crate::onedrive::download::{remove_album_directory, remove_cached};
+use crate::error::{Error, Res};
 
 const READ_SHARE_URL: &str = "https://graph.microsoft.com/v1.0/shares/u!";
 const READ_CONTENTS_URL: &str = "https://graph.microsoft.com/v1.0/drives/";
@@ -20,7 +23,7 @@ pub struct AlbumDriveItem {
     pub name: String,
 }
 
-#[derive(Debug, Clone, rkyv::Serialize, rkyv::Deserialize, rkyv::Archive)]
+#[derive(Debug, Clone, Serialize, Deserialize, rkyv::Serialize, rkyv::Deserialize, rkyv::Archive)]
 pub struct Album {
     pub id: usize,
     pub onedrive_id: String,
@@ -47,7 +50,11 @@ pub struct AlbumMetadata {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AlbumContentsResponse {
-    value: Vec<PhotoResponse>
+    value: Vec<PhotoResponse>,
+
+    // Present while there are more pages of the listing to fetch
+    #[serde(rename = "@odata.nextLink")]
+    next_link: Option<String>
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -58,6 +65,12 @@ struct PhotoResponse {
     id: String,
     name: String,
     size: usize,
+
+    // Change with anything about the item, and only with its content
+    #[serde(rename = "eTag")]
+    etag: Option<String>,
+    #[serde(rename = "cTag")]
+    ctag: Option<String>,
     
     #[serde(rename = "image")]
     resolution_data: ResolutionData,
@@ -78,7 +91,40 @@ pub struct LocationData {
     pub longitude: f64
 }
 
-#[derive(Clone, Debug, rkyv::Serialize, rkyv::Deserialize, rkyv::Archive)]
+/// The versions Graph gives a drive item, kept to tell when a cached file is out of date.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ItemTags {
+    pub etag: Option<String>,
+    pub ctag: Option<String>
+}
+
+/// What syncing an album changed in the local copy of it.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, rkyv::Serialize, rkyv::Deserialize, rkyv::Archive)]
+pub struct SyncReport {
+    // Photos in the album once synced
+    pub photos: u64,
+    pub added: u64,
+    // Photos whose content changed, and are downloaded again when next needed
+    pub updated: u64,
+    pub removed: u64,
+    // Removed photos that were in no other album, whose records and cached files are gone
+    pub deleted: u64
+}
+
+impl SyncReport {
+    /// The changes of two syncs together
+    pub fn merge(self, other: SyncReport) -> SyncReport {
+        SyncReport {
+            photos: self.photos + other.photos,
+            added: self.added + other.added,
+            updated: self.updated + other.updated,
+            removed: self.removed + other.removed,
+            deleted: self.deleted + other.deleted
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, rkyv::Serialize, rkyv::Deserialize, rkyv::Archive)]
 pub struct Photo {
     pub id: usize,
     pub onedrive_id: String,
@@ -91,8 +137,9 @@ pub struct Photo {
 }
 
 impl Photo {
-    fn from_response(response: PhotoResponse) -> Photo {
-        Photo {
+    fn from_response(response: PhotoResponse) -> (Photo, ItemTags) {
+        let tags = ItemTags { etag: response.etag, ctag: response.ctag };
+        (Photo {
             id: 0,
             onedrive_id: response.id,
             name: response.name,
@@ -106,36 +153,47 @@ impl Photo {
             height: response.resolution_data.height,
             filesize: response.size,
             location: response.location
-        }
+        }, tags)
     }
 }
 
-pub async fn new_album(access_token: AccessToken, drive_id: String, share_link: String, database: DataLink) -> Res<(Album, Vec<Photo>)> {
-    let encoded_link = BASE64_URL_SAFE_NO_PAD.encode(&share_link);
-    let drive_item = make_request::<AlbumDriveItem>(&format!("{READ_SHARE_URL}{encoded_link}/driveItem"), access_token.get().to_string(), vec![]).await?;
-    let album = insert_album(database.clone(), Album::from_response(drive_item, share_link)).await?;
-    check_album(access_token, drive_id, album, database).await
+pub async fn new_album(access_token: AccessToken, drive_id: String, share_link: String, database: DataLink, album_root_dir: PathBuf) -> Res<(Album, Vec<Photo>, SyncReport)> {
+    let drive_item = resolve_share_link(&access_token, &share_link).await?;
+    let album = upsert_album(database.clone(), Album::from_response(drive_item, share_link)).await?;
+    check_album(access_token, drive_id, album, database, album_root_dir).await
 }
 
-// TODO PAGINATION
+/// The drive item a share link points to
+async fn resolve_share_link(access_token: &AccessToken, share_link: &str) -> Res<AlbumDriveItem> {
+    let encoded_link = BASE64_URL_SAFE_NO_PAD.encode(share_link);
+    make_request::<AlbumDriveItem>(&format!("{READ_SHARE_URL}{encoded_link}/driveItem"), access_token.get().to_string(), vec![]).await
+}
+
+/// Every item in an album, following the listing across pages
+async fn list_album(access_token: AccessToken, drive_id: String, album: &Album) -> Res<Vec<PhotoResponse>> {
+    let mut url = Some(format!("{READ_CONTENTS_URL}{drive_id}/items/{}/children", album.onedrive_id));
+    let mut items = Vec::new();
+
+    while let Some(next) = url {
+        let page = make_request::<AlbumContentsResponse>(&next, access_token.get().to_string(), vec![]).await?;
+        items.extend(page.value);
+        url = page.next_link;
+    }
+
+    Ok(items)
+}
+
+/// Bring the local copy of an album in line with OneDrive: add the photos new to it, update those that changed, and remove those no longer in it.
+pub async fn check_album(access_token: AccessToken, drive_id: String, album: Album, database: DataLink, album_root_dir: PathBuf) -> Res<(Album, Vec<Photo>, SyncReport)> {
+    let listing = list_album(access_token, drive_id, &album).await?;
+    let remote: HashSet<String> = listing.iter().map(|item| item.id.clone()).collect();
+    let (_, existing) = select_photos_in_album(database.clone(), album.id).await?;
+    let known: HashSet<usize> = existing.iter().map(|photo| photo.id).collect();
 
-pub async fn check_album(access_token: AccessToken, drive_id: String, album: Album, database: DataLink) -> Res<(Album, Vec<Photo>)> {
     let (error_send, error_recv) = unbounded();
-    let stream: Vec<Photo> = stream::iter(make_request::<AlbumContentsResponse>(
-        &format!(
-            "{READ_CONTENTS_URL}{}/items/{}/children",
-            drive_id,
-            &album.onedrive_id
-        ),
-        access_token.get().to_string(),
-        vec![]
-    )
-        .await?
-        .value
-        .into_iter()
-        .map(Photo::from_response))
-        .filter_map(async |photo| match insert_photo(database.clone(), photo).await {
-            Ok(photo) => Some(photo),
+    let upserted: Vec<(Photo, bool)> = stream::iter(listing.into_iter().map(Photo::from_response))
+        .filter_map(async |(photo, tags)| match upsert_photo(database.clone(), photo, tags).await {
+            Ok(upserted) => Some(upserted),
             Err(error) => {
                 let _ = error_send.send(error).await;
                 None
@@ -148,25 +206,101 @@ pub async fn check_album(access_token: AccessToken, drive_id: String, album: Alb
         eprintln!("SQL Error: {error:?}");
     }
 
-    for photo in &stream {
+    let mut updated = 0;
+    for (photo, _) in upserted.iter().filter(|(_, changed)| *changed) {
+        invalidate_cached(database.clone(), photo, album_root_dir.clone()).await?;
+        updated += 1;
+    }
+
+    let stream: Vec<Photo> = upserted.into_iter().map(|(photo, _)| photo).collect();
+    let mut added = 0;
+    for photo in stream.iter().filter(|photo| !known.contains(&photo.id)) {
         insert_entry(database.clone(), album.id, photo.id).await?;
+        added += 1;
     }
 
+    // Compared against the listing rather than what was inserted, so a photo that failed to insert is not taken for a deleted one
+    let stale: Vec<Photo> = existing.into_iter().filter(|photo| !remote.contains(&photo.onedrive_id)).collect();
+    let removed = stale.len() as u64;
+    let deleted = remove_photos(database, &album, stale, album_root_dir).await?;
+
+    let report = SyncReport { photos: stream.len() as u64, added, updated, removed, deleted };
     Ok((
         album,
-        stream
+        stream,
+        report
     ))
 }
 
-// Update all cached albums
-pub async fn check_all_albums(access_token: AccessToken, drive_id: String, database: DataLink) -> Res<Vec<(Album, Vec<Photo>)>> {
-    Ok(stream::iter(
-        select_albums(database.clone())
-            .await?
-            .into_iter()
-    )
-        .filter_map(async |album|
-            check_album(access_token.clone(), drive_id.clone(), album.clone(), database.clone()).await.ok())
-        .collect()
-        .await)
+/// Delete every cached copy of a photo whose content changed, so it is downloaded again when next needed
+async fn invalidate_cached(database: DataLink, photo: &Photo, album_root_dir: PathBuf) -> Res<()> {
+    for album in select_albums_with_photo(database, photo.id).await? {
+        if let Err(error) = remove_cached(photo.clone(), album.onedrive_id, album_root_dir.clone()).await {
+            eprintln!("Unable to remove cached {}: {error:?}", photo.name);
+        }
+    }
+
+    Ok(())
+}
+
+/// Take photos out of an album along with their cached files, deleting those left in no album at all.
+/// Returns how many were deleted.
+async fn remove_photos(database: DataLink, album: &Album, photos: Vec<Photo>, album_root_dir: PathBuf) -> Res<u64> {
+    let mut deleted = 0;
+
+    for photo in photos {
+        delete_entry(database.clone(), album.id, photo.id).await?;
+        if let Err(error) = remove_cached(photo.clone(), album.onedrive_id.clone(), album_root_dir.clone()).await {
+            eprintln!("Unable to remove cached {}: {error:?}", photo.name);
+        }
+
+        if count_entries_for_photo(database.clone(), photo.id).await? == 0 {
+            delete_photo(database.clone(), photo.id).await?;
+            deleted += 1;
+        }
+    }
+
+    Ok(deleted)
+}
+
+/// Delete an album that no longer exists on OneDrive, its photos that are in no other album, and its cached files.
+async fn remove_album(database: DataLink, album: &Album, album_root_dir: PathBuf) -> Res<()> {
+    let (_, photos) = select_photos_in_album(database.clone(), album.id).await?;
+    remove_photos(database.clone(), album, photos, album_root_dir.clone()).await?;
+    delete_album(database, album.id).await?;
+    remove_album_directory(album.onedrive_id.clone(), album_root_dir).await
+}
+
+/// Whether the album's share link no longer resolves either. Only then is an album whose listing was not found taken to be
+/// deleted, as a listing can also go missing while an item is being moved or before every replica has caught up.
+async fn album_is_gone(access_token: &AccessToken, album: &Album) -> Res<bool> {
+    match resolve_share_link(access_token, &album.share_link).await {
+        Ok(_) => Ok(false),
+        Err(Error::OnedriveError(error)) if matches!(error.as_ref(), OnedriveError::NotFound) => Ok(true),
+        Err(error) => Err(error)
+    }
+}
+
+/// Update all cached albums, returning those synced and those removed for no longer existing.
+/// An album that fails to sync for any other reason, or whose share link still resolves, is left as it is.
+pub async fn check_all_albums(access_token: AccessToken, drive_id: String, database: DataLink, album_root_dir: PathBuf) -> Res<(Vec<(Album, Vec<Photo>, SyncReport)>, Vec<Album>)> {
+    let mut synced = Vec::new();
+    let mut removed = Vec::new();
+
+    for album in select_albums(database.clone()).await? {
+        match check_album(access_token.clone(), drive_id.clone(), album.clone(), database.clone(), album_root_dir.clone()).await {
+            Ok(result) => synced.push(result),
+            Err(Error::OnedriveError(error)) if matches!(error.as_ref(), OnedriveError::NotFound) => match album_is_gone(&access_token, &album).await {
+                Ok(true) => {
+                    remove_album(database.clone(), &album, album_root_dir.clone()).await?;
+                    removed.push(album);
+                },
+                Ok(false) => eprintln!("Unable to sync {}: its listing was not found, but its share link still resolves", album.name),
+                Err(error) => eprintln!("Unable to sync {}: {error:?}", album.name)
+            },
+            Err(error) => eprintln!("Unable to sync {}: {error:?}", album.name)
+        }
+    }
+
+    Ok((synced, removed))
 }